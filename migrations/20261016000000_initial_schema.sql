@@ -0,0 +1,27 @@
+-- The tables every later migration builds on. Databases created before migrations existed
+-- already have them, so each statement is a no-op there.
+CREATE TABLE IF NOT EXISTS users (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR(255) NOT NULL UNIQUE,
+    password VARCHAR(255) NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS budgets (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR(255) NOT NULL,
+    settings JSONB NOT NULL DEFAULT '{}'
+);
+
+CREATE TABLE IF NOT EXISTS expenses (
+    id SERIAL PRIMARY KEY,
+    budgetid INTEGER NOT NULL REFERENCES budgets(id) ON DELETE CASCADE,
+    date DATE NOT NULL,
+    description VARCHAR(255) NOT NULL,
+    amount NUMERIC NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS user_budgets (
+    userid INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    budgetid INTEGER NOT NULL REFERENCES budgets(id) ON DELETE CASCADE,
+    PRIMARY KEY (userid, budgetid)
+);
@@ -0,0 +1,3 @@
+-- Every association has had full control over its budget so far, so all of them start as owners.
+ALTER TABLE user_budgets
+    ADD COLUMN IF NOT EXISTS role TEXT NOT NULL DEFAULT 'owner' CHECK (role IN ('owner', 'member'));
@@ -0,0 +1,2 @@
+-- Drafts are kept out of totals until they are committed.
+ALTER TABLE expenses ADD COLUMN IF NOT EXISTS draft BOOLEAN NOT NULL DEFAULT false;
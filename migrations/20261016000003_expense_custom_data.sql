@@ -0,0 +1,2 @@
+-- Values for the custom fields a budget defines in its settings.
+ALTER TABLE expenses ADD COLUMN IF NOT EXISTS custom_data JSONB;
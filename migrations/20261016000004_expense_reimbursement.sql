@@ -0,0 +1,3 @@
+ALTER TABLE expenses
+    ADD COLUMN IF NOT EXISTS reimbursed BOOLEAN NOT NULL DEFAULT false,
+    ADD COLUMN IF NOT EXISTS reimbursed_at DATE;
@@ -0,0 +1,8 @@
+-- One-time expenses that a daily job moves into expenses once their date arrives.
+CREATE TABLE IF NOT EXISTS scheduled_expenses (
+    id SERIAL PRIMARY KEY,
+    budgetid INTEGER NOT NULL REFERENCES budgets(id),
+    date DATE NOT NULL,
+    description TEXT NOT NULL,
+    amount NUMERIC NOT NULL
+);
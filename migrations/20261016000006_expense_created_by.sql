@@ -0,0 +1,3 @@
+-- Expenses outlive the users who recorded them.
+ALTER TABLE expenses
+    ADD COLUMN IF NOT EXISTS created_by INTEGER REFERENCES users(id) ON DELETE SET NULL;
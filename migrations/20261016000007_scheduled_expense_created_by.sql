@@ -0,0 +1,2 @@
+ALTER TABLE scheduled_expenses
+    ADD COLUMN IF NOT EXISTS created_by INTEGER REFERENCES users(id) ON DELETE SET NULL;
@@ -0,0 +1,3 @@
+-- Every existing row is an expense.
+ALTER TABLE expenses
+    ADD COLUMN IF NOT EXISTS entry_type TEXT NOT NULL DEFAULT 'expense' CHECK (entry_type IN ('expense', 'income'));
@@ -0,0 +1,9 @@
+-- Remembers which expense a client's Idempotency-Key created, so a retried POST returns it
+-- instead of inserting a duplicate. expense_id is filled in once the expense exists.
+CREATE TABLE IF NOT EXISTS idempotency_keys (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    key TEXT NOT NULL,
+    expense_id INTEGER REFERENCES expenses(id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, key)
+);
@@ -0,0 +1,5 @@
+-- Names are matched trimmed and case-insensitively, so store them trimmed and keep "Bob" and
+-- "bob" from both registering. This fails if existing names already collide that way; rename
+-- one of each colliding pair before applying it.
+UPDATE users SET name = TRIM(name) WHERE name <> TRIM(name);
+CREATE UNIQUE INDEX IF NOT EXISTS users_name_lower_key ON users (LOWER(name));
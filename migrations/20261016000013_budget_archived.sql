@@ -0,0 +1 @@
+ALTER TABLE budgets ADD COLUMN IF NOT EXISTS archived BOOLEAN NOT NULL DEFAULT false;
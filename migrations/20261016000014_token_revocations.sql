@@ -0,0 +1,6 @@
+-- Tokens signed out before they expire. Rows can be dropped once expires_at passes, since
+-- the token is refused as expired from then on anyway.
+CREATE TABLE IF NOT EXISTS token_revocations (
+    jti UUID PRIMARY KEY,
+    expires_at TIMESTAMPTZ NOT NULL
+);
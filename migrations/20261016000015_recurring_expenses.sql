@@ -0,0 +1,12 @@
+-- Templates for expenses that repeat, such as rent. A daily job records an expense dated
+-- next_run once it arrives and moves next_run on by the cadence.
+CREATE TABLE IF NOT EXISTS recurring_expenses (
+    id SERIAL PRIMARY KEY,
+    budgetid INTEGER NOT NULL REFERENCES budgets(id) ON DELETE CASCADE,
+    description TEXT NOT NULL,
+    amount NUMERIC NOT NULL,
+    cadence TEXT NOT NULL CHECK (cadence IN ('weekly', 'monthly', 'yearly')),
+    next_run DATE NOT NULL,
+    day_of_month INTEGER CHECK (day_of_month BETWEEN 1 AND 31),
+    created_by INTEGER REFERENCES users(id) ON DELETE SET NULL
+);
@@ -0,0 +1,4 @@
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email VARCHAR(254);
+
+-- Addresses are unique regardless of case; users without one don't collide.
+CREATE UNIQUE INDEX IF NOT EXISTS users_email_key ON users (LOWER(email));
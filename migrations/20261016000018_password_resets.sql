@@ -0,0 +1,10 @@
+-- One-time tokens for resetting a forgotten password. A token is spent once used_at is set and
+-- can be dropped once expires_at passes.
+CREATE TABLE IF NOT EXISTS password_resets (
+    token UUID PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS password_resets_user_id_idx ON password_resets (user_id);
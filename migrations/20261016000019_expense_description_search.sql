@@ -0,0 +1,2 @@
+-- Backs GET /expenses/search. Queries must use the same expression for the index to apply.
+CREATE INDEX IF NOT EXISTS expenses_description_search_idx ON expenses USING GIN (to_tsvector('english', description));
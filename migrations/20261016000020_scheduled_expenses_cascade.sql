@@ -0,0 +1,4 @@
+-- Scheduled expenses go with their budget, like expenses and recurring expenses already do.
+ALTER TABLE scheduled_expenses DROP CONSTRAINT IF EXISTS scheduled_expenses_budgetid_fkey;
+ALTER TABLE scheduled_expenses
+    ADD CONSTRAINT scheduled_expenses_budgetid_fkey FOREIGN KEY (budgetid) REFERENCES budgets(id) ON DELETE CASCADE;
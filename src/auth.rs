@@ -1,49 +1,97 @@
+use std::env;
 use std::fmt;
+use std::sync::OnceLock;
 use warp::{Rejection, reject, Filter};
 use serde::{Deserialize, Serialize};
 use jsonwebtoken::{decode, DecodingKey, Validation, errors::ErrorKind};
+use uuid::Uuid;
+use crate::utils::db_rejection;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub user_id: i32,
+    /// The username when the token was issued. Older tokens carry an empty name.
+    #[serde(default)]
+    pub name: String,
     pub exp: usize,
+    /// Identifies the token so it can be revoked. Tokens issued before revocation existed have
+    /// none and stay valid until they expire.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<Uuid>,
 }
 
 #[derive(Debug)]
-enum AuthError {
-    MissingToken,
-    InvalidToken,
-    ExpiredToken,
+pub enum AuthError {
+    Missing,
+    Invalid,
+    Expired,
+    Revoked,
 }
 
 impl fmt::Display for AuthError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            AuthError::MissingToken => write!(f, "Authorization token is missing"),
-            AuthError::InvalidToken => write!(f, "Authorization token is invalid"),
-            AuthError::ExpiredToken => write!(f, "Authorization token is expired"),
+            AuthError::Missing => write!(f, "Authorization token is missing"),
+            AuthError::Invalid => write!(f, "Authorization token is invalid"),
+            AuthError::Expired => write!(f, "Authorization token is expired"),
+            AuthError::Revoked => write!(f, "Authorization token has been revoked"),
         }
     }
 }
 
 impl reject::Reject for AuthError {}
 
-pub fn with_auth() -> impl Filter<Extract = (Claims,), Error = Rejection> + Clone {
+/// The publicly known signing key that only `ALLOW_DEFAULT_SECRET=true` permits, for local
+/// development.
+const DEFAULT_SECRET: &str = "default_secret";
+
+/// The key tokens are signed and verified with, from `JWT_SECRET`. `main` resolves it at startup,
+/// so a deploy without a real secret fails to boot instead of accepting forgeable tokens.
+pub fn jwt_secret() -> &'static str {
+    static SECRET: OnceLock<String> = OnceLock::new();
+    SECRET.get_or_init(|| {
+        let allow_default = env::var("ALLOW_DEFAULT_SECRET").is_ok_and(|value| value == "true");
+        match env::var("JWT_SECRET") {
+            Ok(secret) if !secret.is_empty() && secret != DEFAULT_SECRET => secret,
+            _ if allow_default => {
+                log::warn!("JWT_SECRET is unset or the default; tokens can be forged by anyone");
+                DEFAULT_SECRET.to_string()
+            },
+            _ => panic!("JWT_SECRET must be set to a secret value; set ALLOW_DEFAULT_SECRET=true to use the default in development"),
+        }
+    })
+}
+
+/// Decodes the bearer token and turns away tokens that have been revoked by logging out.
+pub fn with_auth(pool: sqlx::PgPool) -> impl Filter<Extract = (Claims,), Error = Rejection> + Clone {
     warp::header::optional::<String>("authorization")
-        .and_then(|authorization: Option<String>| async move {
-            let token = match authorization {
-                Some(token) => token.replace("Bearer ", ""),
-                None => return Err(reject::custom(AuthError::MissingToken)),
-            };
-
-            let secret = std::env::var("JWT_SECRET").unwrap_or_else(|_| "default_secret".to_string());
-
-            match decode::<Claims>(&token, &DecodingKey::from_secret(secret.as_ref()), &Validation::default()) {
-                Ok(data) => Ok(data.claims),
-                Err(err) => match *err.kind() {
-                    ErrorKind::ExpiredSignature => Err(reject::custom(AuthError::ExpiredToken)),
-                    _ => Err(reject::custom(AuthError::InvalidToken)),
-                },
+        .and_then(move |authorization: Option<String>| {
+            let pool = pool.clone();
+            async move {
+                let token = match authorization {
+                    Some(token) => token.replace("Bearer ", ""),
+                    None => return Err(reject::custom(AuthError::Missing)),
+                };
+
+                let claims = match decode::<Claims>(&token, &DecodingKey::from_secret(jwt_secret().as_ref()), &Validation::default()) {
+                    Ok(data) => data.claims,
+                    Err(err) => return match *err.kind() {
+                        ErrorKind::ExpiredSignature => Err(reject::custom(AuthError::Expired)),
+                        _ => Err(reject::custom(AuthError::Invalid)),
+                    },
+                };
+
+                if let Some(jti) = claims.jti {
+                    let revoked = sqlx::query!("SELECT 1 as exists FROM token_revocations WHERE jti = $1", jti)
+                        .fetch_optional(&pool)
+                        .await
+                        .map_err(db_rejection)?;
+                    if revoked.is_some() {
+                        return Err(reject::custom(AuthError::Revoked));
+                    }
+                }
+
+                Ok(claims)
             }
         })
 }
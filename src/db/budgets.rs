@@ -1,8 +1,11 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde::de::Error as _;
 use serde_json::json;
-use sqlx::postgres::PgPoolOptions;
+use bigdecimal::{BigDecimal, Signed};
+use time::{Date, OffsetDateTime};
 use warp::{Filter, http::StatusCode};
-use crate::utils::{json_body, with_db, user_owns_budget, ServiceError};
+use std::str::FromStr;
+use crate::utils::{json_body, with_db, begin, user_owns_budget, user_is_budget_owner, budget_role, user_owns_budgets, parse_id_list, escape_like, month_bounds, parse_date, validate_date_range, validate_custom_fields, validate_locale, count_days, locale_format, budget_currency, validate_currency, validate_http_url, DEFAULT_LOCALE, db_rejection, ServiceError};
 use crate::auth::{with_auth, Claims};
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -10,12 +13,433 @@ struct Budget {
     id: i32,
     name: String,
     settings: serde_json::Value,
+    locked: bool,
+    archived: bool,
+}
+
+/// A budget as returned to clients, with the currency it is kept in.
+#[derive(Serialize, Debug)]
+struct BudgetResponse {
+    #[serde(flatten)]
+    budget: Budget,
+    currency: &'static str,
+    currency_symbol: &'static str,
+}
+
+impl From<Budget> for BudgetResponse {
+    fn from(budget: Budget) -> Self {
+        let (currency, currency_symbol) = budget_currency(&budget.settings);
+        BudgetResponse { budget, currency, currency_symbol }
+    }
 }
 
 #[derive(Deserialize, Debug)]
 struct NewBudget {
+    name: String,
+    #[serde(default)]
+    settings: BudgetSettings,
+}
+
+/// The `settings` a client sends with a budget. Known keys are typed so malformed values are
+/// rejected with a 400, and any of them may be left out. Other keys are stored as given.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct BudgetSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    currency: Option<String>,
+    #[serde(default, deserialize_with = "decimal_setting", skip_serializing_if = "Option::is_none")]
+    limit: Option<BigDecimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    color: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    period_start_day: Option<u8>,
+    #[serde(default, deserialize_with = "decimal_setting", skip_serializing_if = "Option::is_none")]
+    goal_amount: Option<BigDecimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    goal_date: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    webhook_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timezone: Option<String>,
+    #[serde(flatten)]
+    other: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Accepts a decimal as either a JSON number or a numeric string, without a detour through `f64`.
+fn decimal_setting<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<BigDecimal>, D::Error> {
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Null => Ok(None),
+        value => BudgetService::settings_decimal(&value)
+            .map(Some)
+            .ok_or_else(|| D::Error::custom(format!("invalid decimal: {}", value))),
+    }
+}
+
+impl BudgetSettings {
+    /// Checks values that parsed but make no sense, and returns the settings as they are stored.
+    /// A goal date needs an amount to aim for.
+    fn to_json(&self) -> Result<serde_json::Value, String> {
+        if let Some(currency) = &self.currency {
+            validate_currency(currency)?;
+        }
+        if self.limit.as_ref().is_some_and(|limit| limit.is_negative()) {
+            return Err("limit must not be negative".into());
+        }
+        if let Some(color) = &self.color {
+            let hex = color.strip_prefix('#').unwrap_or_default();
+            if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err("color must be formatted as #RRGGBB".into());
+            }
+        }
+        if self.period_start_day.is_some_and(|day| !(1..=31).contains(&day)) {
+            return Err("period_start_day must be between 1 and 31".into());
+        }
+        if self.goal_amount.as_ref().is_some_and(|goal| !goal.is_positive()) {
+            return Err("goal_amount must be a number greater than zero".into());
+        }
+        if let Some(goal_date) = &self.goal_date {
+            if parse_date(goal_date).is_none() {
+                return Err("goal_date must be formatted as YYYY-MM-DD".into());
+            }
+            if self.goal_amount.is_none() {
+                return Err("goal_date requires goal_amount".into());
+            }
+        }
+
+        if let Some(webhook_url) = &self.webhook_url {
+            validate_http_url("webhook_url", webhook_url)?;
+        }
+
+        let settings = serde_json::to_value(self).map_err(|e| e.to_string())?;
+        validate_custom_fields(&settings)?;
+        validate_locale(&settings)?;
+        count_days(&settings)?;
+        Ok(settings)
+    }
+
+    /// `to_json`, plus the checks that need a lookup: a `timezone` must be a zone name Postgres
+    /// knows, such as `Europe/Paris`, and a `webhook_url` must resolve to public addresses only.
+    async fn validated(&self, pool: &sqlx::PgPool) -> Result<serde_json::Value, warp::Rejection> {
+        let settings = self.to_json().map_err(|e| warp::reject::custom(ServiceError::BadRequest(e)))?;
+
+        if let Some(timezone) = &self.timezone {
+            let known = sqlx::query_scalar!(
+                r#"SELECT EXISTS (SELECT 1 FROM pg_timezone_names WHERE name = $1) AS "known!""#,
+                timezone
+            )
+                .fetch_one(pool)
+                .await
+                .map_err(db_rejection)?;
+            if !known {
+                return Err(warp::reject::custom(ServiceError::BadRequest(
+                    "timezone must be a time zone name such as Europe/Paris".into(),
+                )));
+            }
+        }
+
+        if let Some(webhook_url) = &self.webhook_url {
+            crate::webhooks::check_destination(webhook_url)
+                .await
+                .map_err(|e| warp::reject::custom(ServiceError::BadRequest(e)))?;
+        }
+
+        Ok(settings)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct MyBudgetsQuery {
+    sort: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct BudgetActivity {
+    id: i32,
     name: String,
     settings: serde_json::Value,
+    last_activity: Option<Date>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SettingsMatrixQuery {
+    budgetids: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+struct BudgetSettingsRow {
+    id: i32,
+    name: String,
+    limit: Option<BigDecimal>,
+    currency: &'static str,
+    period_start_day: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct FrequentDescriptionsQuery {
+    limit: Option<i64>,
+    prefix: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct FrequentDescription {
+    description: String,
+    count: i64,
+}
+
+#[derive(Deserialize, Debug)]
+struct SummaryQuery {
+    start_date: Date,
+    end_date: Option<Date>,
+}
+
+#[derive(Serialize, Debug)]
+struct BudgetTotal {
+    id: i32,
+    name: String,
+    total: BigDecimal,
+}
+
+#[derive(Serialize, Debug)]
+struct UserSummary {
+    budget_count: i64,
+    expense_count: i64,
+    total: BigDecimal,
+    top_budgets: Vec<BudgetTotal>,
+    top_categories: Vec<CategoryTotal>,
+}
+
+#[derive(Serialize, Debug)]
+struct BudgetActivityCount {
+    id: i32,
+    name: String,
+    expense_count: i64,
+}
+
+#[derive(Serialize, Debug)]
+struct UserStats {
+    budget_count: i64,
+    expense_count: i64,
+    total: BigDecimal,
+    average_budget_total: BigDecimal,
+    most_active_budget: Option<BudgetActivityCount>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RollingQuery {
+    window: Option<i64>,
+    start_date: Date,
+    end_date: Option<Date>,
+    #[serde(default)]
+    include_drafts: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct RollingPoint {
+    date: Date,
+    total: BigDecimal,
+    rolling_total: BigDecimal,
+    rolling_average: Option<BigDecimal>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OverLimitQuery {
+    period: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ClosingQuery {
+    month: String,
+}
+
+#[derive(Serialize, Debug)]
+struct ClosingReport {
+    budget_id: i32,
+    budget_name: String,
+    currency: &'static str,
+    period_start: Date,
+    period_end: Date,
+    prior_total: BigDecimal,
+    total: BigDecimal,
+    count: i64,
+    limit: Option<BigDecimal>,
+    over_limit: bool,
+    by_category: Vec<CategoryTotal>,
+}
+
+/// Spending in one category, where expenses without a category share a `null` one.
+#[derive(Serialize, Debug)]
+struct CategoryTotal {
+    category: Option<String>,
+    total: BigDecimal,
+    count: i64,
+}
+
+/// How long it has been since a category last saw an expense.
+#[derive(Serialize, Debug)]
+struct CategoryStaleness {
+    category: Option<String>,
+    last_date: Date,
+    days_since: i32,
+}
+
+#[derive(Deserialize, Debug)]
+struct BalanceSheetQuery {
+    as_of: Option<Date>,
+}
+
+#[derive(Serialize, Debug)]
+struct BalanceSheetRow {
+    #[serde(flatten)]
+    category: CategoryTotal,
+    percent: Option<BigDecimal>,
+}
+
+/// `percent` is each category's share of `total`, and `None` when nothing has been spent.
+#[derive(Serialize, Debug)]
+struct BalanceSheet {
+    as_of: Date,
+    currency: &'static str,
+    categories: Vec<BalanceSheetRow>,
+    total: BigDecimal,
+    count: i64,
+}
+
+#[derive(Serialize, Debug)]
+struct GoalProgress {
+    goal_amount: Option<BigDecimal>,
+    goal_date: Option<Date>,
+    saved: BigDecimal,
+    percent: Option<BigDecimal>,
+    months_remaining: Option<i32>,
+    required_monthly: Option<BigDecimal>,
+}
+
+#[derive(Serialize, Debug)]
+struct OverLimitContributor {
+    id: i32,
+    date: Date,
+    description: String,
+    amount: BigDecimal,
+    cumulative_total: BigDecimal,
+    tipped_over: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct OverLimitReport {
+    period_start: Date,
+    period_end: Date,
+    limit: Option<BigDecimal>,
+    total: BigDecimal,
+    contributors: Vec<OverLimitContributor>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CashflowQuery {
+    start_date: Date,
+    end_date: Option<Date>,
+    period: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct CashflowPoint {
+    period: Date,
+    income: BigDecimal,
+    expense: BigDecimal,
+    net: BigDecimal,
+}
+
+#[derive(Deserialize, Debug)]
+struct ByMemberQuery {
+    start_date: Date,
+    end_date: Option<Date>,
+}
+
+#[derive(Serialize, Debug)]
+struct MemberTotal {
+    user_id: Option<i32>,
+    name: Option<String>,
+    count: i64,
+    total: BigDecimal,
+}
+
+#[derive(Serialize, Debug)]
+struct BudgetChecksum {
+    budget_id: i32,
+    count: i64,
+    algorithm: &'static str,
+    checksum: String,
+}
+
+#[derive(Serialize, Debug)]
+struct FormatHints {
+    locale: String,
+    currency: &'static str,
+    currency_symbol: &'static str,
+    decimal_separator: &'static str,
+    thousands_separator: &'static str,
+    currency_position: &'static str,
+}
+
+#[derive(Serialize, Debug)]
+struct BudgetBalance {
+    income: BigDecimal,
+    expense: BigDecimal,
+    net: BigDecimal,
+}
+
+#[derive(Deserialize, Debug)]
+struct StatusQuery {
+    month: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct BudgetStatus {
+    limit: Option<BigDecimal>,
+    total: BigDecimal,
+    remaining: Option<BigDecimal>,
+    over_budget: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct BudgetsQuery {
+    #[serde(default)]
+    include_archived: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct CloneQuery {
+    #[serde(default)]
+    with_expenses: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct TransferRequest {
+    user_id: i32,
+}
+
+#[derive(Serialize, Debug)]
+struct BudgetPermissions {
+    can_view: bool,
+    can_edit: bool,
+    can_delete: bool,
+    can_manage_members: bool,
+    role: Option<String>,
+}
+
+impl BudgetPermissions {
+    /// Members can view the budget; only an owner can change or delete it and manage who it is
+    /// shared with.
+    fn for_role(role: Option<&str>) -> Self {
+        let is_owner = role == Some("owner");
+        BudgetPermissions {
+            can_view: role.is_some(),
+            can_edit: is_owner,
+            can_delete: is_owner,
+            can_manage_members: is_owner,
+            role: role.map(str::to_string),
+        }
+    }
 }
 
 pub struct BudgetService {
@@ -23,69 +447,252 @@ pub struct BudgetService {
 }
 
 impl BudgetService {
-    pub async fn new(database_url: &str) -> Self {
-        let pool = PgPoolOptions::new()
-            .max_connections(5)
-            .connect(database_url)
-            .await
-            .expect("Failed to create pool");
-
+    pub fn new(pool: sqlx::PgPool) -> Self {
         BudgetService { pool }
     }
 
     pub fn routes(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         let pool = self.pool.clone();
         let get_budgets = warp::path("budgets")
+            .and(warp::path::end())
             .and(warp::get())
-            .and(with_auth())
+            .and(with_auth(pool.clone()))
+            .and(warp::query::<BudgetsQuery>())
             .and(with_db(pool.clone()))
             .and_then(Self::handle_get_budgets);
 
         let get_budget = warp::path!("budgets" / i32)
             .and(warp::get())
-            .and(with_auth())
+            .and(with_auth(pool.clone()))
             .and(with_db(pool.clone()))
             .and_then(Self::handle_get_budget);
 
+        let get_my_budgets = warp::path!("me" / "budgets")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(warp::query::<MyBudgetsQuery>())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_my_budgets);
+
+        let get_settings_matrix = warp::path!("me" / "budgets" / "settings-matrix")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(warp::query::<SettingsMatrixQuery>())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_settings_matrix);
+
+        let get_summary = warp::path!("me" / "summary")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(warp::query::<SummaryQuery>())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_summary);
+
+        let get_stats = warp::path!("me" / "stats")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_stats);
+
+        let get_permissions = warp::path!("budgets" / i32 / "permissions")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_permissions);
+
+        let get_rolling = warp::path!("budgets" / i32 / "rolling")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(warp::query::<RollingQuery>())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_rolling);
+
+        let get_balance_sheet = warp::path!("budgets" / i32 / "balance-sheet")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(warp::query::<BalanceSheetQuery>())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_balance_sheet);
+
+        let get_category_staleness = warp::path!("budgets" / i32 / "category-staleness")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_category_staleness);
+
+        let get_closing = warp::path!("budgets" / i32 / "closing")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(warp::query::<ClosingQuery>())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_closing);
+
+        let get_goal_progress = warp::path!("budgets" / i32 / "goal-progress")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_goal_progress);
+
+        let get_over_limit = warp::path!("budgets" / i32 / "over-limit-contributors")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(warp::query::<OverLimitQuery>())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_over_limit);
+
+        let get_status = warp::path!("budgets" / i32 / "status")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(warp::query::<StatusQuery>())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_status);
+
+        let get_balance = warp::path!("budgets" / i32 / "balance")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_balance);
+
+        let get_cashflow = warp::path!("budgets" / i32 / "cashflow")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(warp::query::<CashflowQuery>())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_cashflow);
+
+        let get_by_member = warp::path!("budgets" / i32 / "by-member")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(warp::query::<ByMemberQuery>())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_by_member);
+
+        let get_checksum = warp::path!("budgets" / i32 / "checksum")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_checksum);
+
+        let get_format_hints = warp::path!("budgets" / i32 / "format-hints")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_format_hints);
+
+        let get_frequent_descriptions = warp::path!("budgets" / i32 / "frequent-descriptions")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(warp::query::<FrequentDescriptionsQuery>())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_frequent_descriptions);
+
         let create_budget = warp::path("budgets")
+            .and(warp::path::end())
             .and(warp::post())
-            .and(with_auth())
+            .and(with_auth(pool.clone()))
             .and(json_body())
             .and(with_db(pool.clone()))
             .and_then(Self::handle_create_budget);
 
+        let lock_budget = warp::path!("budgets" / i32 / "lock")
+            .and(warp::post())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(|id, claims, pool| Self::handle_set_locked(id, claims, pool, true));
+
+        let unlock_budget = warp::path!("budgets" / i32 / "unlock")
+            .and(warp::post())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(|id, claims, pool| Self::handle_set_locked(id, claims, pool, false));
+
+        let archive_budget = warp::path!("budgets" / i32 / "archive")
+            .and(warp::post())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(|id, claims, pool| Self::handle_set_archived(id, claims, pool, true));
+
+        let unarchive_budget = warp::path!("budgets" / i32 / "unarchive")
+            .and(warp::post())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(|id, claims, pool| Self::handle_set_archived(id, claims, pool, false));
+
         let update_budget = warp::path!("budgets" / i32)
             .and(warp::put())
-            .and(with_auth())
+            .and(with_auth(pool.clone()))
             .and(json_body())
             .and(with_db(pool.clone()))
             .and_then(Self::handle_update_budget);
 
+        let transfer_budget = warp::path!("budgets" / i32 / "transfer")
+            .and(warp::post())
+            .and(with_auth(pool.clone()))
+            .and(json_body())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_transfer_budget);
+
+        let clone_budget = warp::path!("budgets" / i32 / "clone")
+            .and(warp::post())
+            .and(with_auth(pool.clone()))
+            .and(warp::query::<CloneQuery>())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_clone_budget);
+
         let delete_budget = warp::path!("budgets" / i32)
             .and(warp::delete())
-            .and(with_auth())
+            .and(with_auth(pool.clone()))
             .and(with_db(pool.clone()))
             .and_then(Self::handle_delete_budget);
 
         get_budgets
             .or(get_budget)
+            .or(get_permissions)
+            .or(get_rolling)
+            .or(get_closing)
+            .or(get_balance_sheet)
+            .or(get_category_staleness)
+            .or(get_goal_progress)
+            .or(get_over_limit)
+            .or(get_cashflow)
+            .or(get_balance)
+            .or(get_status)
+            .or(get_by_member)
+            .or(get_my_budgets)
+            .or(get_settings_matrix)
+            .or(get_summary)
+            .or(get_stats)
+            .or(get_frequent_descriptions)
+            .or(get_format_hints)
+            .or(get_checksum)
             .or(create_budget)
             .or(update_budget)
+            .or(lock_budget)
+            .or(unlock_budget)
+            .or(archive_budget)
+            .or(unarchive_budget)
+            .or(transfer_budget)
+            .or(clone_budget)
             .or(delete_budget)
     }
 
-    async fn handle_get_budgets(claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+    /// Archived budgets are left out unless `include_archived` is set.
+    async fn handle_get_budgets(claims: Claims, query: BudgetsQuery, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
         let budgets = sqlx::query_as!(
             Budget,
-            "SELECT b.id, b.name, b.settings
+            "SELECT b.id, b.name, b.settings, b.locked, b.archived
              FROM budgets b
              JOIN user_budgets ub ON b.id = ub.budgetid
-             WHERE ub.userid = $1",
-            claims.user_id
+             WHERE ub.userid = $1 AND ($2 OR NOT b.archived)
+             ORDER BY b.id",
+            claims.user_id,
+            query.include_archived
         )
             .fetch_all(&pool)
             .await
-            .map_err(|e| warp::reject::custom(ServiceError::DatabaseError(e)))?;
+            .map_err(db_rejection)?;
+
+        let budgets: Vec<BudgetResponse> = budgets.into_iter().map(BudgetResponse::from).collect();
 
         Ok(warp::reply::with_status(warp::reply::json(&budgets), StatusCode::OK))
     }
@@ -100,68 +707,847 @@ impl BudgetService {
 
         let budget = sqlx::query_as!(
             Budget,
-            "SELECT id, name, settings FROM budgets WHERE id = $1",
+            "SELECT id, name, settings, locked, archived FROM budgets WHERE id = $1",
             id
         )
             .fetch_one(&pool)
             .await
-            .map_err(|e| warp::reject::custom(ServiceError::DatabaseError(e)))?;
+            .map_err(db_rejection)?;
 
-        Ok(warp::reply::with_status(warp::reply::json(&budget), StatusCode::OK))
+        Ok(warp::reply::with_status(warp::reply::json(&BudgetResponse::from(budget)), StatusCode::OK))
     }
 
-    async fn handle_create_budget(claims: Claims, new_budget: NewBudget, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
-        let mut tx = pool.begin().await.map_err(|e| warp::reject::custom(ServiceError::DatabaseError(e)))?;
+    /// Lists the caller's budgets by `sort`: `activity` (most recent expense first, default) or `name`.
+    async fn handle_get_my_budgets(claims: Claims, query: MyBudgetsQuery, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let sort = query.sort.as_deref().unwrap_or("activity");
+        if !["activity", "name"].contains(&sort) {
+            return Err(warp::reject::custom(ServiceError::BadRequest("sort must be one of activity, name".into())));
+        }
 
-        let budget = sqlx::query_as!(
-            Budget,
-            "INSERT INTO budgets (name, settings) VALUES ($1, $2)
-             RETURNING id, name, settings",
-            new_budget.name,
-            new_budget.settings
+        let budgets = sqlx::query_as!(
+            BudgetActivity,
+            "SELECT b.id, b.name, b.settings, MAX(e.date) AS last_activity
+             FROM budgets b
+             JOIN user_budgets ub ON ub.budgetid = b.id AND ub.userid = $1
+             LEFT JOIN expenses e ON e.budgetid = b.id AND NOT e.draft
+             GROUP BY b.id
+             ORDER BY CASE WHEN $2 = 'name' THEN b.name END, MAX(e.date) DESC NULLS LAST, b.id",
+            claims.user_id,
+            sort
         )
-            .fetch_one(&mut *tx)
+            .fetch_all(&pool)
             .await
-            .map_err(|e| warp::reject::custom(ServiceError::DatabaseError(e)))?;
+            .map_err(db_rejection)?;
 
-        sqlx::query!(
-            "INSERT INTO user_budgets (userid, budgetid) VALUES ($1, $2)",
+        Ok(warp::reply::with_status(warp::reply::json(&budgets), StatusCode::OK))
+    }
+
+    /// Key settings of the caller's budgets side by side, 100 per page by default (at most 500).
+    async fn handle_get_settings_matrix(claims: Claims, query: SettingsMatrixQuery, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let limit = query.limit.unwrap_or(100).clamp(1, 500);
+        let offset = query.offset.unwrap_or(0).max(0);
+
+        let budget_ids = match query.budgetids.as_deref() {
+            Some(ids) => {
+                let ids = parse_id_list(ids)?;
+                let owned = user_owns_budgets(claims.user_id, &ids, &pool).await?;
+                if ids.iter().any(|id| !owned.contains(id)) {
+                    return Ok(warp::reply::with_status(
+                        warp::reply::json(&json!({"error": "Unauthorized"})),
+                        StatusCode::UNAUTHORIZED,
+                    ));
+                }
+                Some(ids)
+            },
+            None => None,
+        };
+
+        let rows: Vec<BudgetSettingsRow> = sqlx::query!(
+            "SELECT b.id, b.name, b.settings
+             FROM budgets b
+             JOIN user_budgets ub ON ub.budgetid = b.id AND ub.userid = $1
+             WHERE $2::INTEGER[] IS NULL OR b.id = ANY($2)
+             ORDER BY b.id
+             LIMIT $3 OFFSET $4",
             claims.user_id,
-            budget.id
+            budget_ids.as_deref(),
+            limit,
+            offset
         )
-            .execute(&mut *tx)
+            .fetch_all(&pool)
             .await
-            .map_err(|e| warp::reject::custom(ServiceError::DatabaseError(e)))?;
-
-        tx.commit().await.map_err(|e| warp::reject::custom(ServiceError::DatabaseError(e)))?;
+            .map_err(db_rejection)?
+            .into_iter()
+            .map(|record| BudgetSettingsRow {
+                id: record.id,
+                name: record.name,
+                limit: record.settings.get("limit").and_then(Self::settings_decimal),
+                currency: budget_currency(&record.settings).0,
+                period_start_day: record.settings.get("period_start_day").and_then(|d| d.as_i64()),
+            })
+            .collect();
 
-        Ok(warp::reply::with_status(warp::reply::json(&budget), StatusCode::CREATED))
+        Ok(warp::reply::with_status(warp::reply::json(&rows), StatusCode::OK))
     }
 
-    async fn handle_update_budget(id: i32, claims: Claims, new_budget: NewBudget, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
-        if !user_owns_budget(claims.user_id, id, &pool, ServiceError::Unauthorized).await? {
-            return Ok(warp::reply::with_status(
-                warp::reply::json(&json!({"error": "Unauthorized"})),
-                StatusCode::UNAUTHORIZED,
-            ));
-        }
+    /// Account-wide spend across every budget the caller belongs to, with the five biggest budgets
+    /// and the five biggest categories. Categories of the same name are combined across budgets.
+    async fn handle_get_summary(claims: Claims, query: SummaryQuery, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        validate_date_range(query.start_date, query.end_date)?;
 
-        let budget = sqlx::query_as!(
-            Budget,
-            "UPDATE budgets SET name = $1, settings = $2 WHERE id = $3
-             RETURNING id, name, settings",
-            new_budget.name,
-            new_budget.settings,
-            id
+        let totals = sqlx::query!(
+            r#"
+            SELECT COUNT(DISTINCT ub.budgetid) AS "budget_count!",
+                   COUNT(e.id) AS "expense_count!",
+                   COALESCE(SUM(e.amount), 0) AS "total!"
+            FROM user_budgets ub
+            LEFT JOIN expenses e ON e.budgetid = ub.budgetid
+                AND NOT e.draft
+                AND e.entry_type = 'expense'
+                AND e.date >= $2
+                AND ($3::DATE IS NULL OR e.date <= $3)
+            WHERE ub.userid = $1
+            "#,
+            claims.user_id,
+            query.start_date,
+            query.end_date
         )
             .fetch_one(&pool)
             .await
-            .map_err(|e| warp::reject::custom(ServiceError::DatabaseError(e)))?;
+            .map_err(db_rejection)?;
+
+        let top_budgets = sqlx::query_as!(
+            BudgetTotal,
+            r#"
+            SELECT b.id, b.name, SUM(e.amount) AS "total!"
+            FROM budgets b
+            JOIN user_budgets ub ON ub.budgetid = b.id AND ub.userid = $1
+            JOIN expenses e ON e.budgetid = b.id
+            WHERE NOT e.draft
+              AND e.entry_type = 'expense'
+              AND e.date >= $2
+              AND ($3::DATE IS NULL OR e.date <= $3)
+            GROUP BY b.id
+            ORDER BY 3 DESC, b.id
+            LIMIT 5
+            "#,
+            claims.user_id,
+            query.start_date,
+            query.end_date
+        )
+            .fetch_all(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        let top_categories = sqlx::query_as!(
+            CategoryTotal,
+            r#"
+            SELECT e.category, SUM(e.amount) AS "total!", COUNT(*) AS "count!"
+            FROM user_budgets ub
+            JOIN expenses e ON e.budgetid = ub.budgetid
+            WHERE ub.userid = $1
+              AND NOT e.draft
+              AND e.entry_type = 'expense'
+              AND e.date >= $2
+              AND ($3::DATE IS NULL OR e.date <= $3)
+            GROUP BY e.category
+            ORDER BY 2 DESC, e.category NULLS LAST
+            LIMIT 5
+            "#,
+            claims.user_id,
+            query.start_date,
+            query.end_date
+        )
+            .fetch_all(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        let summary = UserSummary {
+            budget_count: totals.budget_count,
+            expense_count: totals.expense_count,
+            total: totals.total,
+            top_budgets,
+            top_categories,
+        };
 
-        Ok(warp::reply::with_status(warp::reply::json(&budget), StatusCode::OK))
+        Ok(warp::reply::with_status(warp::reply::json(&summary), StatusCode::OK))
     }
 
-    async fn handle_delete_budget(id: i32, claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+    /// All-time totals across every budget the caller belongs to. Every `user_budgets` row counts,
+    /// so shared budgets are included alongside ones the caller created. Drafts and income are excluded.
+    async fn handle_get_stats(claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let totals = sqlx::query!(
+            r#"
+            SELECT COUNT(*) AS "budget_count!",
+                   COALESCE(SUM(per_budget.expense_count), 0)::BIGINT AS "expense_count!",
+                   COALESCE(SUM(per_budget.total), 0) AS "total!",
+                   COALESCE(ROUND(AVG(per_budget.total), 2), 0) AS "average_budget_total!"
+            FROM (
+                SELECT ub.budgetid,
+                       COUNT(e.id) AS expense_count,
+                       COALESCE(SUM(e.amount), 0) AS total
+                FROM user_budgets ub
+                LEFT JOIN expenses e ON e.budgetid = ub.budgetid AND NOT e.draft AND e.entry_type = 'expense'
+                WHERE ub.userid = $1
+                GROUP BY ub.budgetid
+            ) per_budget
+            "#,
+            claims.user_id
+        )
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        let most_active_budget = sqlx::query_as!(
+            BudgetActivityCount,
+            r#"
+            SELECT b.id, b.name, COUNT(e.id) AS "expense_count!"
+            FROM budgets b
+            JOIN user_budgets ub ON ub.budgetid = b.id AND ub.userid = $1
+            JOIN expenses e ON e.budgetid = b.id AND NOT e.draft AND e.entry_type = 'expense'
+            GROUP BY b.id
+            ORDER BY 3 DESC, b.id
+            LIMIT 1
+            "#,
+            claims.user_id
+        )
+            .fetch_optional(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        let stats = UserStats {
+            budget_count: totals.budget_count,
+            expense_count: totals.expense_count,
+            total: totals.total,
+            average_budget_total: totals.average_budget_total,
+            most_active_budget,
+        };
+
+        Ok(warp::reply::with_status(warp::reply::json(&stats), StatusCode::OK))
+    }
+
+    async fn handle_get_permissions(id: i32, claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let role = budget_role(claims.user_id, id, &pool).await?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&BudgetPermissions::for_role(role.as_deref())), StatusCode::OK))
+    }
+
+    /// Daily spend with a trailing sum and average over the last `window` days (default 7),
+    /// including days before `start_date` so the first points cover a full window.
+    /// The average divides the window's total by the days in it that the budget's `count_days`
+    /// setting counts. Spending on other days still counts towards the total. The average is
+    /// null when the window has no counted days.
+    async fn handle_get_rolling(id: i32, claims: Claims, query: RollingQuery, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let window = query.window.unwrap_or(7);
+        if !(1..=365).contains(&window) {
+            return Err(warp::reject::custom(ServiceError::BadRequest("window must be between 1 and 365".into())));
+        }
+        validate_date_range(query.start_date, query.end_date)?;
+
+        if !user_owns_budget(claims.user_id, id, &pool, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let budget = sqlx::query!("SELECT settings FROM budgets WHERE id = $1", id)
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+        // Settings saved before count_days was validated fall back to counting every day.
+        let counted_days = count_days(&budget.settings).unwrap_or_else(|_| (1..=7).collect());
+
+        let points: Vec<RollingPoint> = sqlx::query!(
+            r#"
+            WITH daily AS (
+                SELECT d::DATE AS day, COALESCE(SUM(e.amount), 0) AS total
+                FROM generate_series($2::DATE - ($4::BIGINT - 1)::INTEGER, COALESCE($3::DATE, CURRENT_DATE), INTERVAL '1 day') d
+                LEFT JOIN expenses e ON e.budgetid = $1 AND e.date = d::DATE AND e.entry_type = 'expense' AND ($5 OR NOT e.draft)
+                GROUP BY d
+            ), rolling AS (
+                SELECT day, total,
+                       SUM(total) OVER w AS rolling_total,
+                       SUM(total) OVER w / NULLIF(COUNT(*) FILTER (WHERE EXTRACT(ISODOW FROM day)::INTEGER = ANY($6)) OVER w, 0) AS rolling_average
+                FROM daily
+                WINDOW w AS (ORDER BY day ROWS BETWEEN $4 - 1 PRECEDING AND CURRENT ROW)
+            )
+            SELECT day AS "day!", total AS "total!", rolling_total AS "rolling_total!", rolling_average AS "rolling_average?"
+            FROM rolling
+            WHERE day >= $2
+            ORDER BY day
+            "#,
+            id,
+            query.start_date,
+            query.end_date,
+            window,
+            query.include_drafts,
+            &counted_days
+        )
+            .fetch_all(&pool)
+            .await
+            .map_err(db_rejection)?
+            .into_iter()
+            .map(|record| RollingPoint {
+                date: record.day,
+                total: record.total,
+                rolling_total: record.rolling_total,
+                rolling_average: record.rolling_average.map(|average| average.round(2)),
+            })
+            .collect();
+
+        Ok(warp::reply::with_status(warp::reply::json(&points), StatusCode::OK))
+    }
+
+    /// Machine-readable month-end report; `prior_total` is everything spent before the period and
+    /// `by_category` splits the period's total, largest first.
+    async fn handle_get_closing(id: i32, claims: Claims, query: ClosingQuery, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let (period_start, period_end) = month_bounds(&query.month).ok_or_else(|| {
+            warp::reject::custom(ServiceError::BadRequest("month must be formatted as YYYY-MM".into()))
+        })?;
+
+        if !user_owns_budget(claims.user_id, id, &pool, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let record = sqlx::query!(
+            r#"
+            SELECT b.name, b.settings,
+                   COALESCE(SUM(e.amount) FILTER (WHERE e.date < $2), 0) AS "prior_total!",
+                   COALESCE(SUM(e.amount) FILTER (WHERE e.date BETWEEN $2 AND $3), 0) AS "total!",
+                   COUNT(e.id) FILTER (WHERE e.date BETWEEN $2 AND $3) AS "count!"
+            FROM budgets b
+            LEFT JOIN expenses e ON e.budgetid = b.id AND NOT e.draft AND e.entry_type = 'expense'
+            WHERE b.id = $1
+            GROUP BY b.id
+            "#,
+            id,
+            period_start,
+            period_end
+        )
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        let by_category = Self::category_totals(id, Some(period_start), period_end, &pool).await?;
+
+        let (currency, _) = budget_currency(&record.settings);
+        let limit = record.settings.get("limit").and_then(Self::settings_decimal);
+        let over_limit = limit.as_ref().is_some_and(|limit| &record.total > limit);
+
+        let report = ClosingReport {
+            budget_id: id,
+            budget_name: record.name,
+            currency,
+            period_start,
+            period_end,
+            prior_total: record.prior_total,
+            total: record.total,
+            count: record.count,
+            limit,
+            over_limit,
+            by_category,
+        };
+
+        Ok(warp::reply::with_status(warp::reply::json(&report), StatusCode::OK))
+    }
+
+    /// Committed spend per category from `start` (or the beginning) through `end`, largest first.
+    async fn category_totals(id: i32, start: Option<Date>, end: Date, pool: &sqlx::PgPool) -> Result<Vec<CategoryTotal>, warp::Rejection> {
+        sqlx::query_as!(
+            CategoryTotal,
+            r#"
+            SELECT category, SUM(amount) AS "total!", COUNT(*) AS "count!"
+            FROM expenses
+            WHERE budgetid = $1
+              AND ($2::DATE IS NULL OR date >= $2)
+              AND date <= $3
+              AND NOT draft
+              AND entry_type = 'expense'
+            GROUP BY category
+            ORDER BY 2 DESC, category NULLS LAST
+            "#,
+            id,
+            start,
+            end
+        )
+            .fetch_all(pool)
+            .await
+            .map_err(db_rejection)
+    }
+
+    /// Spend to date per category as of `as_of` (default today, UTC), with each category's
+    /// share of the whole and the grand total.
+    async fn handle_get_balance_sheet(id: i32, claims: Claims, query: BalanceSheetQuery, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        if !user_owns_budget(claims.user_id, id, &pool, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let budget = sqlx::query!("SELECT settings FROM budgets WHERE id = $1", id)
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+        let (currency, _) = budget_currency(&budget.settings);
+
+        let as_of = query.as_of.unwrap_or_else(|| OffsetDateTime::now_utc().date());
+        let categories = Self::category_totals(id, None, as_of, &pool).await?;
+        let total: BigDecimal = categories.iter().map(|category| &category.total).sum();
+        let count = categories.iter().map(|category| category.count).sum();
+
+        let categories = categories
+            .into_iter()
+            .map(|category| BalanceSheetRow {
+                percent: total.is_positive().then(|| (&category.total * BigDecimal::from(100) / &total).round(2)),
+                category,
+            })
+            .collect();
+
+        let sheet = BalanceSheet {
+            as_of,
+            currency,
+            categories,
+            total,
+            count,
+        };
+
+        Ok(warp::reply::with_status(warp::reply::json(&sheet), StatusCode::OK))
+    }
+
+    /// The latest committed expense in each category and the days since, stalest first. Today
+    /// is taken in the budget's `timezone` setting, or UTC without one.
+    async fn handle_get_category_staleness(id: i32, claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        if !user_owns_budget(claims.user_id, id, &pool, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        // Zone names are checked when settings are saved, but budgets saved before the setting
+        // existed may carry anything under that key.
+        let staleness = sqlx::query_as!(
+            CategoryStaleness,
+            r#"
+            WITH today AS (
+                SELECT (now() AT TIME ZONE COALESCE(
+                    (SELECT name FROM pg_timezone_names WHERE name = b.settings->>'timezone'),
+                    'UTC'
+                ))::DATE AS today
+                FROM budgets b
+                WHERE b.id = $1
+            )
+            SELECT e.category, MAX(e.date) AS "last_date!", (today.today - MAX(e.date))::INTEGER AS "days_since!"
+            FROM expenses e, today
+            WHERE e.budgetid = $1
+              AND NOT e.draft
+              AND e.entry_type = 'expense'
+            GROUP BY e.category, today.today
+            ORDER BY 3 DESC, e.category NULLS LAST
+            "#,
+            id
+        )
+            .fetch_all(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&staleness), StatusCode::OK))
+    }
+
+    /// Net savings (income less expenses, drafts excluded) against the budget's `goal_amount`.
+    /// `required_monthly` spreads what is left over the months until `goal_date`, counting the
+    /// current one, so a goal date already reached asks for the whole remainder now.
+    async fn handle_get_goal_progress(id: i32, claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        if !user_owns_budget(claims.user_id, id, &pool, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let record = sqlx::query!(
+            r#"
+            SELECT b.settings,
+                   COALESCE(SUM(CASE WHEN e.entry_type = 'income' THEN e.amount ELSE -e.amount END), 0) AS "saved!"
+            FROM budgets b
+            LEFT JOIN expenses e ON e.budgetid = b.id AND NOT e.draft
+            WHERE b.id = $1
+            GROUP BY b.id
+            "#,
+            id
+        )
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        // Settings saved before goals were validated may hold a goal of zero or less.
+        let goal_amount = record.settings.get("goal_amount").and_then(Self::settings_decimal).filter(|goal| goal.is_positive());
+        let goal_date = record.settings.get("goal_date").and_then(|date| date.as_str()).and_then(parse_date);
+        let percent = goal_amount.as_ref().map(|goal| (&record.saved * BigDecimal::from(100) / goal).round(2));
+
+        let today = OffsetDateTime::now_utc().date();
+        let months_remaining = goal_date.map(|date| {
+            ((date.year() - today.year()) * 12 + date.month() as i32 - today.month() as i32 + 1).max(1)
+        });
+        let required_monthly = goal_amount.as_ref().zip(months_remaining).map(|(goal, months)| {
+            let remaining = (goal - &record.saved).max(BigDecimal::from(0));
+            (remaining / BigDecimal::from(months)).round(2)
+        });
+
+        let progress = GoalProgress {
+            goal_amount,
+            goal_date,
+            saved: record.saved,
+            percent,
+            months_remaining,
+            required_monthly,
+        };
+
+        Ok(warp::reply::with_status(warp::reply::json(&progress), StatusCode::OK))
+    }
+
+    /// The expenses in `period` (YYYY-MM) that pushed the running total past the budget's limit,
+    /// newest first, with the one that first crossed it marked `tipped_over`. Empty when the
+    /// budget has no limit or stayed within it.
+    async fn handle_get_over_limit(id: i32, claims: Claims, query: OverLimitQuery, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let (period_start, period_end) = month_bounds(&query.period).ok_or_else(|| {
+            warp::reject::custom(ServiceError::BadRequest("period must be formatted as YYYY-MM".into()))
+        })?;
+
+        if !user_owns_budget(claims.user_id, id, &pool, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let budget = sqlx::query!("SELECT settings FROM budgets WHERE id = $1", id)
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+        let limit = budget.settings.get("limit").and_then(Self::settings_decimal);
+
+        let rows = sqlx::query!(
+            r#"
+            SELECT id, date, description, amount, SUM(amount) OVER (ORDER BY date, id) AS "cumulative_total!"
+            FROM expenses
+            WHERE budgetid = $1
+              AND NOT draft
+              AND entry_type = 'expense'
+              AND date BETWEEN $2 AND $3
+            ORDER BY date, id
+            "#,
+            id,
+            period_start,
+            period_end
+        )
+            .fetch_all(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        let total = rows.last().map_or_else(|| BigDecimal::from(0), |row| row.cumulative_total.clone());
+        let mut contributors: Vec<OverLimitContributor> = match &limit {
+            Some(limit) => rows
+                .into_iter()
+                .filter(|row| &row.cumulative_total > limit)
+                .map(|row| OverLimitContributor {
+                    tipped_over: &(&row.cumulative_total - &row.amount) <= limit,
+                    id: row.id,
+                    date: row.date,
+                    description: row.description,
+                    amount: row.amount,
+                    cumulative_total: row.cumulative_total,
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        contributors.reverse();
+
+        let report = OverLimitReport {
+            period_start,
+            period_end,
+            limit,
+            total,
+            contributors,
+        };
+
+        Ok(warp::reply::with_status(warp::reply::json(&report), StatusCode::OK))
+    }
+
+    /// Spend against the `limit` setting, either all-time or for `month` (YYYY-MM) when given.
+    /// Without a limit, `remaining` is null and the budget is never over.
+    async fn handle_get_status(id: i32, claims: Claims, query: StatusQuery, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let bounds = match &query.month {
+            Some(month) => Some(month_bounds(month).ok_or_else(|| {
+                warp::reject::custom(ServiceError::BadRequest("month must be formatted as YYYY-MM".into()))
+            })?),
+            None => None,
+        };
+
+        if !user_owns_budget(claims.user_id, id, &pool, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let record = sqlx::query!(
+            r#"
+            SELECT b.settings,
+                   COALESCE(SUM(e.amount), 0) AS "total!"
+            FROM budgets b
+            LEFT JOIN expenses e ON e.budgetid = b.id
+                AND NOT e.draft
+                AND e.entry_type = 'expense'
+                AND ($2::DATE IS NULL OR e.date BETWEEN $2 AND $3)
+            WHERE b.id = $1
+            GROUP BY b.id
+            "#,
+            id,
+            bounds.map(|(start, _)| start),
+            bounds.map(|(_, end)| end)
+        )
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        let limit = record.settings.get("limit").and_then(Self::settings_decimal);
+        let remaining = limit.as_ref().map(|limit| limit - &record.total);
+        let over_budget = limit.as_ref().is_some_and(|limit| &record.total > limit);
+
+        let status = BudgetStatus {
+            limit,
+            total: record.total,
+            remaining,
+            over_budget,
+        };
+
+        Ok(warp::reply::with_status(warp::reply::json(&status), StatusCode::OK))
+    }
+
+    /// All-time income, expense and net (income minus expense), excluding drafts.
+    async fn handle_get_balance(id: i32, claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        if !user_owns_budget(claims.user_id, id, &pool, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let record = sqlx::query!(
+            r#"
+            SELECT COALESCE(SUM(amount) FILTER (WHERE entry_type = 'income'), 0) AS "income!",
+                   COALESCE(SUM(amount) FILTER (WHERE entry_type = 'expense'), 0) AS "expense!"
+            FROM expenses
+            WHERE budgetid = $1 AND NOT draft
+            "#,
+            id
+        )
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        let balance = BudgetBalance {
+            net: &record.income - &record.expense,
+            income: record.income,
+            expense: record.expense,
+        };
+
+        Ok(warp::reply::with_status(warp::reply::json(&balance), StatusCode::OK))
+    }
+
+    /// Income, expense and net per `period` (`week` or `month`, default `month`). Months start on
+    /// the budget's `period_start_day`, so with 15 they run from the 15th to the 14th and are
+    /// labelled by their first day.
+    async fn handle_get_cashflow(id: i32, claims: Claims, query: CashflowQuery, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let period = query.period.as_deref().unwrap_or("month");
+        if !["week", "month"].contains(&period) {
+            return Err(warp::reject::custom(ServiceError::BadRequest("period must be one of week, month".into())));
+        }
+        validate_date_range(query.start_date, query.end_date)?;
+
+        if !user_owns_budget(claims.user_id, id, &pool, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let budget = sqlx::query!("SELECT settings FROM budgets WHERE id = $1", id)
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+        let offset = match period {
+            "month" => budget.settings.get("period_start_day").and_then(|d| d.as_i64()).filter(|d| (1..=31).contains(d)).unwrap_or(1) - 1,
+            _ => 0,
+        };
+
+        let points: Vec<CashflowPoint> = sqlx::query!(
+            r#"
+            SELECT (date_trunc($4, date - $5::INTEGER) + $5::INTEGER * INTERVAL '1 day')::DATE AS "period!",
+                   COALESCE(SUM(amount) FILTER (WHERE entry_type = 'income'), 0) AS "income!",
+                   COALESCE(SUM(amount) FILTER (WHERE entry_type = 'expense'), 0) AS "expense!"
+            FROM expenses
+            WHERE budgetid = $1
+              AND NOT draft
+              AND date >= $2
+              AND ($3::DATE IS NULL OR date <= $3)
+            GROUP BY 1
+            ORDER BY 1
+            "#,
+            id,
+            query.start_date,
+            query.end_date,
+            period,
+            offset as i32
+        )
+            .fetch_all(&pool)
+            .await
+            .map_err(db_rejection)?
+            .into_iter()
+            .map(|record| CashflowPoint {
+                period: record.period,
+                net: &record.income - &record.expense,
+                income: record.income,
+                expense: record.expense,
+            })
+            .collect();
+
+        Ok(warp::reply::with_status(warp::reply::json(&points), StatusCode::OK))
+    }
+
+    /// Count and total of the expenses each member entered, by `created_by` rather than who paid.
+    /// Expenses with no recorded creator are grouped under a null `user_id`.
+    async fn handle_get_by_member(id: i32, claims: Claims, query: ByMemberQuery, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        validate_date_range(query.start_date, query.end_date)?;
+
+        if !user_owns_budget(claims.user_id, id, &pool, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let members = sqlx::query_as!(
+            MemberTotal,
+            r#"
+            SELECT e.created_by AS user_id, u.name AS "name?", COUNT(*) AS "count!", SUM(e.amount) AS "total!"
+            FROM expenses e
+            LEFT JOIN users u ON u.id = e.created_by
+            WHERE e.budgetid = $1
+              AND NOT e.draft
+              AND e.entry_type = 'expense'
+              AND e.date >= $2
+              AND ($3::DATE IS NULL OR e.date <= $3)
+            GROUP BY e.created_by, u.name
+            ORDER BY 4 DESC, e.created_by NULLS LAST
+            "#,
+            id,
+            query.start_date,
+            query.end_date
+        )
+            .fetch_all(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&members), StatusCode::OK))
+    }
+
+    /// MD5 of the budget's expenses so clients can detect divergence without a full download.
+    /// Each expense, drafts included, becomes the line `id|YYYY-MM-DD|amount|description`, with the
+    /// amount as stored (e.g. `12.50`). Lines are ordered by id and joined with `\n` with no
+    /// trailing newline. A budget with no expenses hashes the empty string.
+    async fn handle_get_checksum(id: i32, claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        if !user_owns_budget(claims.user_id, id, &pool, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let record = sqlx::query!(
+            r#"
+            SELECT COUNT(*) AS "count!",
+                   md5(COALESCE(string_agg(
+                       id || '|' || to_char(date, 'YYYY-MM-DD') || '|' || amount || '|' || description,
+                       E'\n' ORDER BY id
+                   ), '')) AS "checksum!"
+            FROM expenses
+            WHERE budgetid = $1
+            "#,
+            id
+        )
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        let checksum = BudgetChecksum {
+            budget_id: id,
+            count: record.count,
+            algorithm: "md5",
+            checksum: record.checksum,
+        };
+
+        Ok(warp::reply::with_status(warp::reply::json(&checksum), StatusCode::OK))
+    }
+
+    /// How amounts in this budget should be displayed, from its `locale` setting (default en-US).
+    async fn handle_get_format_hints(id: i32, claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        if !user_owns_budget(claims.user_id, id, &pool, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let budget = sqlx::query!("SELECT settings FROM budgets WHERE id = $1", id)
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        // Settings saved before locales were validated may hold anything; fall back to the default.
+        let locale = budget.settings
+            .get("locale")
+            .and_then(|locale| locale.as_str())
+            .filter(|locale| locale_format(locale).is_some())
+            .unwrap_or(DEFAULT_LOCALE);
+        let (decimal_separator, thousands_separator, currency_position) = locale_format(locale)
+            .expect("default locale is supported");
+
+        let (currency, currency_symbol) = budget_currency(&budget.settings);
+
+        let hints = FormatHints {
+            locale: locale.to_string(),
+            currency,
+            currency_symbol,
+            decimal_separator,
+            thousands_separator,
+            currency_position,
+        };
+
+        Ok(warp::reply::with_status(warp::reply::json(&hints), StatusCode::OK))
+    }
+
+    /// Reads a decimal stored in settings either as a JSON number or a string.
+    fn settings_decimal(value: &serde_json::Value) -> Option<BigDecimal> {
+        match value {
+            serde_json::Value::Number(n) => BigDecimal::from_str(&n.to_string()).ok(),
+            serde_json::Value::String(s) => BigDecimal::from_str(s).ok(),
+            _ => None,
+        }
+    }
+
+    /// Most used descriptions, grouped case-insensitively and reported in their most common
+    /// spelling, optionally restricted to those starting with `prefix`.
+    async fn handle_get_frequent_descriptions(id: i32, claims: Claims, query: FrequentDescriptionsQuery, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let limit = query.limit.unwrap_or(10).clamp(1, 100);
+        let prefix = query.prefix.as_deref().map(|prefix| format!("{}%", escape_like(prefix.trim())));
+
         if !user_owns_budget(claims.user_id, id, &pool, ServiceError::Unauthorized).await? {
             return Ok(warp::reply::with_status(
                 warp::reply::json(&json!({"error": "Unauthorized"})),
@@ -169,25 +1555,506 @@ impl BudgetService {
             ));
         }
 
-        let mut tx = pool.begin().await.map_err(|e| warp::reject::custom(ServiceError::DatabaseError(e)))?;
+        let descriptions = sqlx::query_as!(
+            FrequentDescription,
+            r#"
+            SELECT mode() WITHIN GROUP (ORDER BY TRIM(description)) AS "description!", COUNT(*) AS "count!"
+            FROM expenses
+            WHERE budgetid = $1
+              AND ($2::TEXT IS NULL OR TRIM(description) ILIKE $2)
+            GROUP BY LOWER(TRIM(description))
+            ORDER BY 2 DESC, 1
+            LIMIT $3
+            "#,
+            id,
+            prefix,
+            limit
+        )
+            .fetch_all(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&descriptions), StatusCode::OK))
+    }
+
+    async fn handle_create_budget(claims: Claims, new_budget: NewBudget, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let settings = new_budget.settings.validated(&pool).await?;
+
+        let mut tx = pool.begin().await.map_err(db_rejection)?;
+
+        let budget = sqlx::query_as!(
+            Budget,
+            "INSERT INTO budgets (name, settings) VALUES ($1, $2)
+             RETURNING id, name, settings, locked, archived",
+            new_budget.name,
+            settings
+        )
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(db_rejection)?;
+
+        sqlx::query!(
+            "INSERT INTO user_budgets (userid, budgetid, role) VALUES ($1, $2, 'owner')",
+            claims.user_id,
+            budget.id
+        )
+            .execute(&mut *tx)
+            .await
+            .map_err(db_rejection)?;
+
+        tx.commit().await.map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&BudgetResponse::from(budget)), StatusCode::CREATED))
+    }
+
+    /// Copies a budget the caller belongs to into a new one named "<name> (copy)" with the same
+    /// settings, owned by the caller and neither locked nor archived. Expenses are copied too
+    /// with `with_expenses=true`; members are not.
+    async fn handle_clone_budget(id: i32, claims: Claims, query: CloneQuery, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut tx = begin(&pool).await?;
+
+        if !user_owns_budget(claims.user_id, id, &mut *tx, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let budget = sqlx::query_as!(
+            Budget,
+            "INSERT INTO budgets (name, settings)
+             SELECT LEFT(name, 248) || ' (copy)', settings FROM budgets WHERE id = $1
+             RETURNING id, name, settings, locked, archived",
+            id
+        )
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(db_rejection)?;
+
+        sqlx::query!(
+            "INSERT INTO user_budgets (userid, budgetid, role) VALUES ($1, $2, 'owner')",
+            claims.user_id,
+            budget.id
+        )
+            .execute(&mut *tx)
+            .await
+            .map_err(db_rejection)?;
+
+        if query.with_expenses {
+            sqlx::query!(
+                "INSERT INTO expenses (budgetid, date, description, amount, draft, custom_data, reimbursed, reimbursed_at, created_by, category, entry_type, receipt_url)
+                 SELECT $2, date, description, amount, draft, custom_data, reimbursed, reimbursed_at, created_by, category, entry_type, receipt_url
+                 FROM expenses WHERE budgetid = $1 ORDER BY id",
+                id,
+                budget.id
+            )
+                .execute(&mut *tx)
+                .await
+                .map_err(db_rejection)?;
+        }
+
+        tx.commit().await.map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&BudgetResponse::from(budget)), StatusCode::CREATED))
+    }
+
+    async fn handle_update_budget(id: i32, claims: Claims, new_budget: NewBudget, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let settings = new_budget.settings.validated(&pool).await?;
+
+        if !user_is_budget_owner(claims.user_id, id, &pool, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let budget = sqlx::query_as!(
+            Budget,
+            "UPDATE budgets SET name = $1, settings = $2 WHERE id = $3
+             RETURNING id, name, settings, locked, archived",
+            new_budget.name,
+            settings,
+            id
+        )
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&BudgetResponse::from(budget)), StatusCode::OK))
+    }
+
+    /// Freezes or unfreezes a budget; while locked, its expenses can be read but not changed.
+    async fn handle_set_locked(id: i32, claims: Claims, pool: sqlx::PgPool, locked: bool) -> Result<impl warp::Reply, warp::Rejection> {
+        if !user_is_budget_owner(claims.user_id, id, &pool, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let budget = sqlx::query_as!(
+            Budget,
+            "UPDATE budgets SET locked = $1 WHERE id = $2
+             RETURNING id, name, settings, locked, archived",
+            locked,
+            id
+        )
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&BudgetResponse::from(budget)), StatusCode::OK))
+    }
+
+    /// Hides a budget from the budget list, or brings it back, keeping its expenses.
+    async fn handle_set_archived(id: i32, claims: Claims, pool: sqlx::PgPool, archived: bool) -> Result<impl warp::Reply, warp::Rejection> {
+        if !user_is_budget_owner(claims.user_id, id, &pool, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let budget = sqlx::query_as!(
+            Budget,
+            "UPDATE budgets SET archived = $1 WHERE id = $2
+             RETURNING id, name, settings, locked, archived",
+            archived,
+            id
+        )
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&BudgetResponse::from(budget)), StatusCode::OK))
+    }
+
+    /// Hands ownership to another user already associated with the budget; the caller stays on
+    /// as a member.
+    async fn handle_transfer_budget(id: i32, claims: Claims, transfer: TransferRequest, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        if transfer.user_id == claims.user_id {
+            return Err(warp::reject::custom(ServiceError::BadRequest("Cannot transfer a budget to yourself".into())));
+        }
+
+        let mut tx = begin(&pool).await?;
+
+        if !user_is_budget_owner(claims.user_id, id, &mut *tx, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let promoted = sqlx::query!(
+            "UPDATE user_budgets SET role = 'owner' WHERE userid = $1 AND budgetid = $2",
+            transfer.user_id,
+            id
+        )
+            .execute(&mut *tx)
+            .await
+            .map_err(db_rejection)?;
+
+        if promoted.rows_affected() == 0 {
+            return Err(warp::reject::custom(ServiceError::BadRequest(format!(
+                "User {} is not associated with budget {}",
+                transfer.user_id, id
+            ))));
+        }
+
+        sqlx::query!(
+            "UPDATE user_budgets SET role = 'member' WHERE userid = $1 AND budgetid = $2",
+            claims.user_id,
+            id
+        )
+            .execute(&mut *tx)
+            .await
+            .map_err(db_rejection)?;
+
+        tx.commit().await.map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(
+            warp::reply::json(&format!("Transferred budget {} to user {}", id, transfer.user_id)),
+            StatusCode::OK,
+        ))
+    }
+
+    /// Permanently removes a budget and its expenses. The budget must be archived first, so
+    /// nothing is destroyed by a single stray request.
+    async fn handle_delete_budget(id: i32, claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut tx = begin(&pool).await?;
+
+        if !user_is_budget_owner(claims.user_id, id, &mut *tx, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let archived = sqlx::query_scalar!("SELECT archived FROM budgets WHERE id = $1 FOR UPDATE", id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(db_rejection)?;
+
+        if !archived {
+            return Err(warp::reject::custom(ServiceError::Conflict(format!(
+                "Budget {} must be archived before it can be deleted",
+                id
+            ))));
+        }
 
         sqlx::query!("DELETE FROM expenses WHERE budgetid = $1", id)
             .execute(&mut *tx)
             .await
-            .map_err(|e| warp::reject::custom(ServiceError::DatabaseError(e)))?;
+            .map_err(db_rejection)?;
+
+        sqlx::query!("DELETE FROM scheduled_expenses WHERE budgetid = $1", id)
+            .execute(&mut *tx)
+            .await
+            .map_err(db_rejection)?;
 
         sqlx::query!("DELETE FROM user_budgets WHERE budgetid = $1", id)
             .execute(&mut *tx)
             .await
-            .map_err(|e| warp::reject::custom(ServiceError::DatabaseError(e)))?;
+            .map_err(db_rejection)?;
 
         sqlx::query!("DELETE FROM budgets WHERE id = $1", id)
             .execute(&mut *tx)
             .await
-            .map_err(|e| warp::reject::custom(ServiceError::DatabaseError(e)))?;
+            .map_err(db_rejection)?;
 
-        tx.commit().await.map_err(|e| warp::reject::custom(ServiceError::DatabaseError(e)))?;
+        tx.commit().await.map_err(db_rejection)?;
 
         Ok(warp::reply::with_status(warp::reply::json(&format!("Budget with id {} deleted", id)), StatusCode::OK))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use crate::utils::handle_rejection;
+    use time::Month;
+
+    /// March 2024 spending across categories, plus a draft, income and an April expense.
+    async fn seed_categories(pool: &sqlx::PgPool, budget: i32) {
+        sqlx::query(
+            "INSERT INTO expenses (budgetid, date, description, amount, category, draft, entry_type) VALUES
+                 ($1, '2024-03-02', 'Groceries', 40, 'food', FALSE, 'expense'),
+                 ($1, '2024-03-09', 'Takeaway', 25, 'food', FALSE, 'expense'),
+                 ($1, '2024-03-10', 'Bus', 90, 'travel', FALSE, 'expense'),
+                 ($1, '2024-03-11', 'Parking', 5, NULL, FALSE, 'expense'),
+                 ($1, '2024-03-12', 'Unsure', 500, 'food', TRUE, 'expense'),
+                 ($1, '2024-03-15', 'Salary', 1000, 'food', FALSE, 'income'),
+                 ($1, '2024-04-01', 'Groceries', 30, 'food', FALSE, 'expense')",
+        )
+            .bind(budget)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    async fn get_json(pool: &sqlx::PgPool, user: i32, path: &str) -> serde_json::Value {
+        let routes = BudgetService::new(pool.clone()).routes().recover(handle_rejection);
+        let response = warp::test::request().path(path).header("authorization", test_support::bearer(user)).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        serde_json::from_slice(response.body()).unwrap()
+    }
+
+    fn number(value: &serde_json::Value) -> f64 {
+        value.as_str().unwrap().parse().unwrap()
+    }
+
+    #[sqlx::test]
+    async fn closing_report_totals_each_category_without_drafts(pool: sqlx::PgPool) {
+        let user = test_support::user(&pool, "owner").await;
+        let budget = test_support::budget(&pool, user).await;
+        seed_categories(&pool, budget).await;
+
+        let report = get_json(&pool, user, &format!("/budgets/{}/closing?month=2024-03", budget)).await;
+
+        let by_category: Vec<(Option<&str>, f64, i64)> = report["by_category"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| (row["category"].as_str(), number(&row["total"]), row["count"].as_i64().unwrap()))
+            .collect();
+        assert_eq!(by_category, vec![(Some("travel"), 90.0, 1), (Some("food"), 65.0, 2), (None, 5.0, 1)]);
+    }
+
+    #[sqlx::test]
+    async fn balance_sheet_totals_categories_up_to_the_date(pool: sqlx::PgPool) {
+        let user = test_support::user(&pool, "owner").await;
+        let budget = test_support::budget(&pool, user).await;
+        seed_categories(&pool, budget).await;
+
+        let sheet = get_json(&pool, user, &format!("/budgets/{}/balance-sheet?as_of=2024-03-10", budget)).await;
+
+        let categories: Vec<(Option<&str>, f64, f64)> = sheet["categories"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| (row["category"].as_str(), number(&row["total"]), number(&row["percent"])))
+            .collect();
+        assert_eq!(categories, vec![(Some("travel"), 90.0, 58.06), (Some("food"), 65.0, 41.94)]);
+        assert_eq!((number(&sheet["total"]), sheet["count"].as_i64()), (155.0, Some(3)));
+    }
+
+    #[sqlx::test]
+    async fn category_staleness_counts_days_in_the_budget_timezone(pool: sqlx::PgPool) {
+        let user = test_support::user(&pool, "owner").await;
+        let mut days_since = Vec::new();
+        // Kiritimati is always a day or two ahead of Pago Pago.
+        for timezone in ["Pacific/Pago_Pago", "Pacific/Kiritimati"] {
+            let budget = test_support::budget(&pool, user).await;
+            sqlx::query("UPDATE budgets SET settings = jsonb_build_object('timezone', $2::TEXT) WHERE id = $1")
+                .bind(budget)
+                .bind(timezone)
+                .execute(&pool)
+                .await
+                .unwrap();
+            sqlx::query(
+                "INSERT INTO expenses (budgetid, date, description, amount, category) VALUES
+                     ($1, '2024-03-01', 'Electricity', 60, 'utilities'),
+                     ($1, '2024-01-01', 'Water', 20, 'utilities'),
+                     ($1, '2023-12-01', 'Cinema', 12, 'fun')",
+            )
+                .bind(budget)
+                .execute(&pool)
+                .await
+                .unwrap();
+
+            let staleness = get_json(&pool, user, &format!("/budgets/{}/category-staleness", budget)).await;
+            let rows: Vec<(&str, i64)> = staleness
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|row| (row["category"].as_str().unwrap(), row["days_since"].as_i64().unwrap()))
+                .collect();
+            assert_eq!(rows.iter().map(|row| row.0).collect::<Vec<_>>(), vec!["fun", "utilities"]);
+            assert_eq!(rows[0].1 - rows[1].1, 91);
+            days_since.push(rows[1].1);
+        }
+
+        assert!(days_since[1] > days_since[0]);
+    }
+
+    #[sqlx::test]
+    async fn budget_settings_reject_internal_webhook_urls(pool: sqlx::PgPool) {
+        let user = test_support::user(&pool, "owner").await;
+        let budget = test_support::budget(&pool, user).await;
+        let routes = BudgetService::new(pool.clone()).routes().recover(handle_rejection);
+
+        for (url, status) in [
+            ("http://169.254.169.254/latest/meta-data", StatusCode::BAD_REQUEST),
+            ("http://10.0.0.5/hook", StatusCode::BAD_REQUEST),
+            ("http://localhost:8080/hook", StatusCode::BAD_REQUEST),
+            ("https://93.184.216.34/hook", StatusCode::OK),
+        ] {
+            let response = warp::test::request()
+                .method("PUT")
+                .path(&format!("/budgets/{}", budget))
+                .header("authorization", test_support::bearer(user))
+                .json(&json!({"name": "Trip", "settings": {"webhook_url": url}}))
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), status, "{}", url);
+        }
+
+        let settings: serde_json::Value = sqlx::query_scalar("SELECT settings FROM budgets WHERE id = $1")
+            .bind(budget)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(settings["webhook_url"], "https://93.184.216.34/hook");
+    }
+
+    #[sqlx::test]
+    async fn budget_settings_reject_unknown_timezones(pool: sqlx::PgPool) {
+        let user = test_support::user(&pool, "owner").await;
+        let routes = BudgetService::new(pool.clone()).routes().recover(handle_rejection);
+
+        for (timezone, status) in [("Mars/Olympus_Mons", StatusCode::BAD_REQUEST), ("Europe/Paris", StatusCode::CREATED)] {
+            let response = warp::test::request()
+                .method("POST")
+                .path("/budgets")
+                .header("authorization", test_support::bearer(user))
+                .json(&json!({"name": "Trip", "settings": {"timezone": timezone}}))
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), status);
+        }
+    }
+
+    #[sqlx::test]
+    async fn cashflow_months_start_on_the_period_start_day(pool: sqlx::PgPool) {
+        let user = test_support::user(&pool, "owner").await;
+        let budget = test_support::budget(&pool, user).await;
+        sqlx::query("UPDATE budgets SET settings = '{\"period_start_day\": 15}' WHERE id = $1")
+            .bind(budget)
+            .execute(&pool)
+            .await
+            .unwrap();
+        for (date, amount) in [("2024-03-14", "10"), ("2024-03-15", "20"), ("2024-04-14", "40"), ("2024-04-15", "80")] {
+            test_support::expense(&pool, budget, date, amount).await;
+        }
+
+        let day = |month, day| json!(Date::from_calendar_date(2024, month, day).unwrap());
+        let months = get_json(&pool, user, &format!("/budgets/{}/cashflow?start_date=2024-01-01", budget)).await;
+        let months: Vec<(serde_json::Value, f64)> = months
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|point| (point["period"].clone(), number(&point["expense"])))
+            .collect();
+        assert_eq!(
+            months,
+            vec![(day(Month::February, 15), 10.0), (day(Month::March, 15), 60.0), (day(Month::April, 15), 80.0)]
+        );
+
+        // Weeks ignore the setting and start on Monday.
+        let weeks = get_json(&pool, user, &format!("/budgets/{}/cashflow?start_date=2024-01-01&period=week", budget)).await;
+        assert_eq!(weeks[0]["period"], day(Month::March, 11));
+    }
+
+    #[sqlx::test]
+    async fn summary_ranks_categories_across_budgets(pool: sqlx::PgPool) {
+        let user = test_support::user(&pool, "owner").await;
+        let other = test_support::user(&pool, "other").await;
+        let home = test_support::budget(&pool, user).await;
+        let trip = test_support::budget(&pool, user).await;
+        let foreign = test_support::budget(&pool, other).await;
+        for (budget, date, amount, category, entry_type, draft) in [
+            (home, "2024-03-01", 40, Some("food"), "expense", false),
+            (trip, "2024-03-02", 30, Some("food"), "expense", false),
+            (trip, "2024-03-03", 50, Some("travel"), "expense", false),
+            (home, "2024-03-04", 20, None, "expense", false),
+            (home, "2024-03-05", 500, Some("salary"), "income", false),
+            (home, "2024-03-06", 900, Some("gadgets"), "expense", true),
+            (home, "2024-01-01", 900, Some("travel"), "expense", false),
+            (foreign, "2024-03-07", 900, Some("rent"), "expense", false),
+        ] {
+            sqlx::query(
+                "INSERT INTO expenses (budgetid, date, description, amount, category, entry_type, draft)
+                 VALUES ($1, $2::DATE, 'Test expense', $3, $4, $5, $6)",
+            )
+                .bind(budget)
+                .bind(date)
+                .bind(amount)
+                .bind(category)
+                .bind(entry_type)
+                .bind(draft)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+
+        let summary = get_json(&pool, user, "/me/summary?start_date=2024-02-01").await;
+        let categories: Vec<(Option<&str>, f64, i64)> = summary["top_categories"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| (row["category"].as_str(), number(&row["total"]), row["count"].as_i64().unwrap()))
+            .collect();
+        assert_eq!(categories, vec![(Some("food"), 70.0, 2), (Some("travel"), 50.0, 1), (None, 20.0, 1)]);
+    }
+}
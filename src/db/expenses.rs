@@ -1,15 +1,56 @@
-use warp::{Filter, http::StatusCode};
-use sqlx::postgres::PgPoolOptions;
-use crate::utils::{json_body, with_db, user_owns_budget, ServiceError};
+use warp::{Filter, Reply, http::StatusCode};
+use crate::utils::{json_body, with_db, user_owns_budget, user_owns_budgets, parse_id_list, calendar_month, validate_custom_data, validate_date_range, validate_http_url, escape_like, budget_currency, round_amount, budget_lock_state, begin, db_rejection, ServiceError};
 use serde::{Deserialize, Serialize};
-use bigdecimal::BigDecimal;
-use time::Date;
+use bigdecimal::{BigDecimal, Signed};
+use time::{Date, Duration, Month, OffsetDateTime};
 use crate::auth::{with_auth, Claims};
 use serde_json::json;
 
 #[derive(Deserialize, Debug)]
 struct BudgetIdQuery {
     budgetid: i32,
+    #[serde(default)]
+    include_drafts: bool,
+    #[serde(default)]
+    exclude_reimbursed: bool,
+    category: Option<String>,
+    #[serde(default)]
+    signed: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct ExpensesTotal {
+    total: BigDecimal,
+    currency: &'static str,
+    currency_symbol: &'static str,
+}
+
+#[derive(Deserialize, Debug)]
+struct BudgetIdsQuery {
+    budgetids: String,
+    #[serde(default)]
+    include_drafts: bool,
+    #[serde(default)]
+    exclude_reimbursed: bool,
+    category: Option<String>,
+    #[serde(default)]
+    signed: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct BudgetTotal {
+    budgetid: i32,
+    #[serde(flatten)]
+    total: ExpensesTotal,
+}
+
+/// Per-budget totals and their sum. `currency` is only set when every budget shares one, since
+/// the sum of amounts in different currencies has none.
+#[derive(Serialize, Debug)]
+struct CombinedTotal {
+    budgets: Vec<BudgetTotal>,
+    total: BigDecimal,
+    currency: Option<&'static str>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -17,6 +58,95 @@ struct GetExpenseQuery {
     budgetid: i32,
     start_date: Date,
     end_date: Option<Date>,
+    #[serde(default)]
+    running_balance: bool,
+    reimbursed: Option<bool>,
+    created_by: Option<i32>,
+    category: Option<String>,
+    q: Option<String>,
+    min_amount: Option<BigDecimal>,
+    max_amount: Option<BigDecimal>,
+    sort_by: Option<String>,
+    order: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    after: Option<i32>,
+    #[serde(default)]
+    signed: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct MonthExpensesQuery {
+    budgetid: i32,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    #[serde(default)]
+    signed: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct ExportQuery {
+    budgetid: i32,
+    start_date: Option<Date>,
+    end_date: Option<Date>,
+}
+
+#[derive(Deserialize, Debug)]
+struct StatsQuery {
+    budgetid: i32,
+    start_date: Option<Date>,
+    end_date: Option<Date>,
+    #[serde(default)]
+    signed: bool,
+}
+
+/// Average, min and max are `None` when no expenses match.
+#[derive(Serialize, Debug)]
+struct ExpenseStats {
+    count: i64,
+    total: BigDecimal,
+    average: Option<BigDecimal>,
+    min: Option<BigDecimal>,
+    max: Option<BigDecimal>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TrendQuery {
+    budgetid: i32,
+    period: Option<String>,
+    #[serde(default)]
+    signed: bool,
+}
+
+/// `change_percent` is `None` when nothing was spent in the previous period.
+#[derive(Serialize, Debug)]
+struct SpendingTrend {
+    period: String,
+    current_start: Date,
+    previous_start: Date,
+    current: BigDecimal,
+    previous: BigDecimal,
+    change_percent: Option<BigDecimal>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MonthlyQuery {
+    budgetid: i32,
+    year: Option<i32>,
+    #[serde(default)]
+    signed: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct MonthlyTotal {
+    month: Date,
+    total: BigDecimal,
+}
+
+#[derive(Deserialize, Debug)]
+struct SignedQuery {
+    #[serde(default)]
+    signed: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -26,6 +156,35 @@ struct Expense {
     date: Date,
     description: String,
     amount: BigDecimal,
+    draft: bool,
+    custom_data: Option<serde_json::Value>,
+    reimbursed: bool,
+    reimbursed_at: Option<Date>,
+    created_by: Option<i32>,
+    category: Option<String>,
+    entry_type: String,
+    receipt_url: Option<String>,
+}
+
+impl Expense {
+    /// Amounts are stored and returned as magnitudes, with `entry_type` telling income from
+    /// expense, and spend figures count expenses only. With `signed=true`, income is positive and
+    /// expenses negative, in rows and in aggregates alike, so clients can sum amounts directly.
+    fn signed(mut self, signed: bool) -> Self {
+        if signed && self.entry_type == "expense" {
+            self.amount = -self.amount;
+        }
+        self
+    }
+}
+
+/// An expense with the cumulative spend of the listed non-draft entries up to and including it,
+/// accumulated from oldest to newest. Signed, it is the running net of income and expenses.
+#[derive(Serialize, Debug)]
+struct LedgerEntry {
+    #[serde(flatten)]
+    expense: Expense,
+    running_balance: BigDecimal,
 }
 
 #[derive(Deserialize, Debug)]
@@ -34,20 +193,87 @@ struct NewExpense {
     date: Date,
     description: String,
     amount: BigDecimal,
+    #[serde(default)]
+    draft: bool,
+    custom_data: Option<serde_json::Value>,
+    category: Option<String>,
+    #[serde(default)]
+    entry_type: EntryType,
+    receipt_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum EntryType {
+    #[default]
+    Expense,
+    Income,
+}
+
+impl EntryType {
+    fn as_str(self) -> &'static str {
+        match self {
+            EntryType::Expense => "expense",
+            EntryType::Income => "income",
+        }
+    }
+}
+
+/// How far `POST /expenses/shift-dates` may move expenses, in days either way.
+const MAX_SHIFT_DAYS: i32 = 3650;
+
+#[derive(Deserialize, Debug)]
+struct ShiftDates {
+    budgetid: i32,
+    ids: Option<Vec<i32>>,
+    days: i32,
+}
+
+#[derive(Deserialize, Debug)]
+struct DeleteExpenses {
+    ids: Vec<i32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct FindQuery {
+    amount: BigDecimal,
+    tolerance: Option<BigDecimal>,
+    budgetids: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchQuery {
+    q: String,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    after: Option<i32>,
+    #[serde(default)]
+    signed: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct SearchResult {
+    #[serde(flatten)]
+    expense: Expense,
+    budget_name: String,
 }
 
+#[derive(Serialize, Debug)]
+struct BudgetMatches {
+    budgetid: i32,
+    budget_name: String,
+    expenses: Vec<Expense>,
+}
+
+/// How long a client's `Idempotency-Key` keeps returning the expense it first created.
+const IDEMPOTENCY_WINDOW_HOURS: i32 = 24;
+
 pub struct ExpenseService {
     pool: sqlx::PgPool,
 }
 
 impl ExpenseService {
-    pub async fn new(database_url: &str) -> Self {
-        let pool = PgPoolOptions::new()
-            .max_connections(5)
-            .connect(database_url)
-            .await
-            .expect("Failed to create pool");
-
+    pub fn new(pool: sqlx::PgPool) -> Self {
         ExpenseService { pool }
     }
 
@@ -56,51 +282,150 @@ impl ExpenseService {
 
         let get_expenses_total = warp::path!("expenses" / "total")
             .and(warp::query::<BudgetIdQuery>())
-            .and(with_auth())
+            .and(with_auth(pool.clone()))
             .and(with_db(pool.clone()))
             .and_then(Self::handle_get_expenses_total);
 
+        let get_combined_total = warp::path!("expenses" / "total")
+            .and(warp::query::<BudgetIdsQuery>())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_combined_total);
+
         let get_expenses = warp::path("expenses")
+            .and(warp::path::end())
             .and(warp::get())
-            .and(with_auth())
+            .and(with_auth(pool.clone()))
             .and(warp::query::<GetExpenseQuery>())
             .and(with_db(pool.clone()))
             .and_then(Self::handle_get_expenses);
 
+        let get_month_expenses = warp::path!("expenses" / i32 / u32)
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(warp::query::<MonthExpensesQuery>())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_month_expenses);
+
+        let export_expenses = warp::path!("expenses" / "export")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(warp::query::<ExportQuery>())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_export_expenses);
+
+        let get_stats = warp::path!("expenses" / "stats")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(warp::query::<StatsQuery>())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_stats);
+
+        let get_trend = warp::path!("expenses" / "trend")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(warp::query::<TrendQuery>())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_trend);
+
+        let get_monthly = warp::path!("expenses" / "monthly")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(warp::query::<MonthlyQuery>())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_monthly);
+
         let get_expense = warp::path!("expenses" / i32)
             .and(warp::get())
-            .and(with_auth())
+            .and(with_auth(pool.clone()))
+            .and(warp::query::<SignedQuery>())
             .and(with_db(pool.clone()))
             .and_then(Self::handle_get_expense);
 
         let create_expense = warp::path("expenses")
+            .and(warp::path::end())
             .and(warp::post())
-            .and(with_auth())
+            .and(with_auth(pool.clone()))
+            .and(warp::header::optional::<String>("idempotency-key"))
             .and(json_body())
             .and(with_db(pool.clone()))
             .and_then(Self::handle_create_expense);
 
         let update_expense = warp::path!("expenses" / i32)
             .and(warp::put())
-            .and(with_auth())
+            .and(with_auth(pool.clone()))
             .and(json_body())
             .and(with_db(pool.clone()))
             .and_then(Self::handle_update_expense);
 
+        let commit_expense = warp::path!("expenses" / i32 / "commit")
+            .and(warp::post())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_commit_expense);
+
+        let search_expenses = warp::path!("expenses" / "search")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(warp::query::<SearchQuery>())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_search_expenses);
+
+        let find_expenses = warp::path!("me" / "find")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(warp::query::<FindQuery>())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_find_expenses);
+
+        let reimburse_expense = warp::path!("expenses" / i32 / "reimburse")
+            .and(warp::post())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_reimburse_expense);
+
+        let shift_dates = warp::path!("expenses" / "shift-dates")
+            .and(warp::post())
+            .and(with_auth(pool.clone()))
+            .and(json_body())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_shift_dates);
+
         let delete_expense = warp::path!("expenses" / i32)
             .and(warp::delete())
-            .and(with_auth())
+            .and(with_auth(pool.clone()))
             .and(with_db(pool.clone()))
             .and_then(Self::handle_delete_expense);
 
+        let delete_expenses = warp::path("expenses")
+            .and(warp::path::end())
+            .and(warp::delete())
+            .and(with_auth(pool.clone()))
+            .and(json_body())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_delete_expenses);
+
         get_expenses_total
+            .or(get_combined_total)
             .or(get_expenses)
+            .or(get_month_expenses)
+            .or(export_expenses)
+            .or(get_stats)
+            .or(get_trend)
+            .or(search_expenses)
+            .or(get_monthly)
             .or(get_expense)
             .or(create_expense)
             .or(update_expense)
+            .or(commit_expense)
+            .or(reimburse_expense)
+            .or(shift_dates)
             .or(delete_expense)
+            .or(delete_expenses)
+            .or(find_expenses)
     }
 
+    /// Spend with the currency the budget is kept in.
     async fn handle_get_expenses_total(
         query: BudgetIdQuery,
         claims: Claims,
@@ -113,127 +438,1386 @@ impl ExpenseService {
             ));
         }
 
-        let result = sqlx::query!("SELECT COALESCE(SUM(amount), 0) as total FROM expenses WHERE budgetid = $1", query.budgetid)
+        let result = sqlx::query!(
+            r#"SELECT COALESCE(SUM(CASE WHEN $5 AND entry_type = 'expense' THEN -amount ELSE amount END), 0) as total, (SELECT settings FROM budgets WHERE id = $1) AS "settings!" FROM expenses WHERE budgetid = $1 AND ($5 OR entry_type = 'expense') AND ($2 OR NOT draft) AND NOT ($3 AND reimbursed) AND ($4::TEXT IS NULL OR category = $4)"#,
+            query.budgetid,
+            query.include_drafts,
+            query.exclude_reimbursed,
+            query.category,
+            query.signed
+        )
             .fetch_one(&pool)
             .await
-            .map_err(|e| warp::reject::custom(ServiceError::DatabaseError(e)))?;
+            .map_err(db_rejection)?;
 
-        let total: BigDecimal = result.total.unwrap_or_else(|| BigDecimal::from(0));
+        let (currency, currency_symbol) = budget_currency(&result.settings);
+        let total = ExpensesTotal {
+            total: round_amount(&result.total.unwrap_or_else(|| BigDecimal::from(0)), currency),
+            currency,
+            currency_symbol,
+        };
 
         Ok(warp::reply::with_status(warp::reply::json(&total), StatusCode::OK))
     }
 
-    async fn handle_get_expenses(claims: Claims, query: GetExpenseQuery, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
-        if !user_owns_budget(claims.user_id, query.budgetid, &pool, ServiceError::Unauthorized).await? {
+    /// Spend of each of `budgetids` and across all of them, with the same filters as the
+    /// single-budget total. The caller must belong to every listed budget.
+    async fn handle_get_combined_total(
+        query: BudgetIdsQuery,
+        claims: Claims,
+        pool: sqlx::PgPool
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let ids = parse_id_list(&query.budgetids)?;
+        let owned = user_owns_budgets(claims.user_id, &ids, &pool).await?;
+        if ids.iter().any(|id| !owned.contains(id)) {
             return Ok(warp::reply::with_status(
                 warp::reply::json(&json!({"error": "Unauthorized"})),
                 StatusCode::UNAUTHORIZED,
             ));
         }
 
-        let expenses = sqlx::query_as!(
-                Expense,
+        let records = sqlx::query!(
+            r#"
+            SELECT b.id, b.settings, COALESCE(SUM(CASE WHEN $5 AND e.entry_type = 'expense' THEN -e.amount ELSE e.amount END), 0) AS "total!"
+            FROM budgets b
+            LEFT JOIN expenses e ON e.budgetid = b.id
+              AND ($5 OR e.entry_type = 'expense')
+              AND ($2 OR NOT e.draft)
+              AND NOT ($3 AND e.reimbursed)
+              AND ($4::TEXT IS NULL OR e.category = $4)
+            WHERE b.id = ANY($1)
+            GROUP BY b.id
+            ORDER BY b.id
+            "#,
+            &ids,
+            query.include_drafts,
+            query.exclude_reimbursed,
+            query.category,
+            query.signed
+        )
+            .fetch_all(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        let budgets: Vec<BudgetTotal> = records
+            .into_iter()
+            .map(|record| {
+                let (currency, currency_symbol) = budget_currency(&record.settings);
+                BudgetTotal {
+                    budgetid: record.id,
+                    total: ExpensesTotal { total: round_amount(&record.total, currency), currency, currency_symbol },
+                }
+            })
+            .collect();
+
+        let total = budgets.iter().map(|budget| &budget.total.total).sum();
+        let currency = match budgets.first() {
+            Some(first) if budgets.iter().all(|budget| budget.total.currency == first.total.currency) => Some(first.total.currency),
+            _ => None,
+        };
+
+        Ok(warp::reply::with_status(warp::reply::json(&CombinedTotal { budgets, total, currency }), StatusCode::OK))
+    }
+
+    /// The expenses dated within one calendar month, listed and paged like `GET /expenses`.
+    async fn handle_get_month_expenses(year: i32, month: u32, claims: Claims, query: MonthExpensesQuery, pool: sqlx::PgPool) -> Result<warp::reply::Response, warp::Rejection> {
+        if !(1..=12).contains(&month) {
+            return Err(warp::reject::custom(ServiceError::BadRequest("month must be between 1 and 12".into())));
+        }
+        let (start_date, end_date) = calendar_month(year, month as u8)
+            .ok_or_else(|| warp::reject::custom(ServiceError::BadRequest("year is out of range".into())))?;
+
+        let query = GetExpenseQuery {
+            budgetid: query.budgetid,
+            start_date,
+            end_date: Some(end_date),
+            running_balance: false,
+            reimbursed: None,
+            created_by: None,
+            category: None,
+            q: None,
+            min_amount: None,
+            max_amount: None,
+            sort_by: None,
+            order: None,
+            limit: query.limit,
+            offset: query.offset,
+            after: None,
+            signed: query.signed,
+        };
+
+        Self::handle_get_expenses(claims, query, pool).await
+    }
+
+    /// Newest first, one page at a time: `limit` (default 50, at most 200) rows starting at
+    /// `offset` (default 0). `X-Total-Count` holds the number of matching expenses, so clients
+    /// page through by adding `limit` to `offset` until it reaches the total. Running balances
+    /// are computed over every match, not just the page. `q` keeps only expenses whose
+    /// description contains it, ignoring case, and `min_amount`/`max_amount` bound the amount
+    /// inclusively. `sort_by` (`date`, `amount` or `description`) and `order` (`asc` or `desc`)
+    /// change the order, with ties broken by id in the same direction.
+    ///
+    /// In the default order, `after` (an expense id) pages by cursor instead of offset: the page
+    /// starts just past that expense, so rows added meanwhile don't shift it. `X-Next-Cursor`
+    /// holds the value for the next page and is left out once a page comes back short.
+    async fn handle_get_expenses(claims: Claims, query: GetExpenseQuery, pool: sqlx::PgPool) -> Result<warp::reply::Response, warp::Rejection> {
+        validate_date_range(query.start_date, query.end_date)?;
+        let sort_by = query.sort_by.as_deref().unwrap_or("date");
+        if !["date", "amount", "description"].contains(&sort_by) {
+            return Err(warp::reject::custom(ServiceError::BadRequest("sort_by must be one of date, amount, description".into())));
+        }
+        let order = query.order.as_deref().unwrap_or("desc");
+        if !["asc", "desc"].contains(&order) {
+            return Err(warp::reject::custom(ServiceError::BadRequest("order must be one of asc, desc".into())));
+        }
+        if let (Some(min_amount), Some(max_amount)) = (&query.min_amount, &query.max_amount) {
+            if min_amount > max_amount {
+                return Err(warp::reject::custom(ServiceError::BadRequest("min_amount must not be greater than max_amount".into())));
+            }
+        }
+        let search = query.q.as_deref().map(|q| format!("%{}%", escape_like(q.trim())));
+        let limit = query.limit.unwrap_or(50).clamp(1, 200);
+        let offset = query.offset.unwrap_or(0);
+        if offset < 0 {
+            return Err(warp::reject::custom(ServiceError::BadRequest("offset must not be negative".into())));
+        }
+        if query.after.is_some() && (sort_by != "date" || order != "desc" || query.offset.is_some()) {
+            return Err(warp::reject::custom(ServiceError::BadRequest(
+                "after only works with the default date descending order and without offset".into(),
+            )));
+        }
+
+        if !user_owns_budget(claims.user_id, query.budgetid, &pool, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ).into_response());
+        }
+
+        if let Some(created_by) = query.created_by {
+            if !user_owns_budget(created_by, query.budgetid, &pool, ServiceError::Unauthorized).await? {
+                return Err(warp::reject::custom(ServiceError::BadRequest(format!(
+                    "User {} is not a member of budget {}",
+                    created_by, query.budgetid
+                ))));
+            }
+        }
+
+        // The cursor is the position of the last expense seen, so the next page is everything
+        // strictly below it in (date, id) order.
+        let cursor = match query.after {
+            Some(after) => {
+                let date = sqlx::query_scalar!("SELECT date FROM expenses WHERE id = $1 AND budgetid = $2", after, query.budgetid)
+                    .fetch_optional(&pool)
+                    .await
+                    .map_err(db_rejection)?
+                    .ok_or_else(|| warp::reject::custom(ServiceError::BadRequest("after must be the id of an expense in this budget".into())))?;
+                Some((date, after))
+            },
+            None => None,
+        };
+
+        let total = sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*) AS "count!"
+            FROM expenses
+            WHERE budgetid = $1
+              AND date >= $2
+              AND ($3::DATE IS NULL OR date <= $3)
+              AND ($4::BOOLEAN IS NULL OR reimbursed = $4)
+              AND ($5::INTEGER IS NULL OR created_by = $5)
+              AND ($6::TEXT IS NULL OR category = $6)
+              AND ($7::TEXT IS NULL OR description ILIKE $7)
+              AND ($8::NUMERIC IS NULL OR amount >= $8)
+              AND ($9::NUMERIC IS NULL OR amount <= $9)
+            "#,
+            query.budgetid,
+            query.start_date,
+            query.end_date,
+            query.reimbursed,
+            query.created_by,
+            query.category,
+            search,
+            query.min_amount,
+            query.max_amount
+        )
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        let entries: Vec<LedgerEntry> = sqlx::query!(
                 r#"
-                SELECT * FROM expenses
+                SELECT *, SUM(CASE WHEN draft OR NOT ($16 OR entry_type = 'expense') THEN 0 WHEN $16 AND entry_type = 'expense' THEN -amount ELSE amount END) OVER (ORDER BY date, id) AS "running_balance!"
+                FROM expenses
                 WHERE budgetid = $1
                   AND date >= $2
                   AND ($3::DATE IS NULL OR date <= $3)
-                ORDER BY date DESC
+                  AND ($4::BOOLEAN IS NULL OR reimbursed = $4)
+                  AND ($5::INTEGER IS NULL OR created_by = $5)
+                  AND ($6::TEXT IS NULL OR category = $6)
+                  AND ($7::TEXT IS NULL OR description ILIKE $7)
+                  AND ($12::NUMERIC IS NULL OR amount >= $12)
+                  AND ($13::NUMERIC IS NULL OR amount <= $13)
+                  AND ($14::DATE IS NULL OR (date, id) < ($14, $15))
+                ORDER BY CASE WHEN $10 = 'date' AND $11 = 'asc' THEN date END,
+                         CASE WHEN $10 = 'date' AND $11 = 'desc' THEN date END DESC,
+                         CASE WHEN $10 = 'amount' AND $11 = 'asc' THEN amount END,
+                         CASE WHEN $10 = 'amount' AND $11 = 'desc' THEN amount END DESC,
+                         CASE WHEN $10 = 'description' AND $11 = 'asc' THEN description END,
+                         CASE WHEN $10 = 'description' AND $11 = 'desc' THEN description END DESC,
+                         CASE WHEN $11 = 'asc' THEN id END,
+                         id DESC
+                LIMIT $8 OFFSET $9
                 "#,
                 query.budgetid,
                 query.start_date,
-                query.end_date
+                query.end_date,
+                query.reimbursed,
+                query.created_by,
+                query.category,
+                search,
+                limit,
+                offset,
+                sort_by,
+                order,
+                query.min_amount,
+                query.max_amount,
+                cursor.map(|(date, _)| date),
+                cursor.map(|(_, id)| id),
+                query.signed
             )
             .fetch_all(&pool)
             .await
-            .map_err(|e| warp::reject::custom(ServiceError::DatabaseError(e)))?;
+            .map_err(db_rejection)?
+            .into_iter()
+            .map(|record| LedgerEntry {
+                expense: Expense {
+                    id: record.id,
+                    budgetid: record.budgetid,
+                    date: record.date,
+                    description: record.description,
+                    amount: record.amount,
+                    draft: record.draft,
+                    custom_data: record.custom_data,
+                    reimbursed: record.reimbursed,
+                    reimbursed_at: record.reimbursed_at,
+                    created_by: record.created_by,
+                    category: record.category,
+                    entry_type: record.entry_type,
+                    receipt_url: record.receipt_url,
+                }.signed(query.signed),
+                running_balance: record.running_balance,
+            })
+            .collect();
+
+        let next_cursor = match entries.last() {
+            Some(last) if entries.len() as i64 == limit => Some(last.expense.id),
+            _ => None,
+        };
+
+        let body = if query.running_balance {
+            warp::reply::json(&entries)
+        } else {
+            let expenses: Vec<Expense> = entries.into_iter().map(|entry| entry.expense).collect();
+            warp::reply::json(&expenses)
+        };
+
+        let mut response = warp::reply::with_header(
+            warp::reply::with_status(body, StatusCode::OK),
+            "X-Total-Count",
+            total.to_string(),
+        ).into_response();
+        if let Some(next_cursor) = next_cursor {
+            response.headers_mut().insert("X-Next-Cursor", next_cursor.into());
+        }
 
-        Ok(warp::reply::with_status(warp::reply::json(&expenses), StatusCode::OK))
+        Ok(response)
     }
 
-    async fn handle_get_expense(id: i32, claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
-        let expense = sqlx::query_as!(Expense, "SELECT * FROM expenses WHERE id = $1", id)
+    /// Count, sum, average, smallest and largest of the budget's committed expenses, optionally
+    /// limited to a date window. Income is left out. The average is rounded to the budget's currency.
+    async fn handle_get_stats(claims: Claims, query: StatsQuery, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        if let Some(start_date) = query.start_date {
+            validate_date_range(start_date, query.end_date)?;
+        }
+
+        if !user_owns_budget(claims.user_id, query.budgetid, &pool, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let mut stats = sqlx::query_as!(
+            ExpenseStats,
+            r#"
+            SELECT COUNT(*) AS "count!", COALESCE(SUM(amount), 0) AS "total!", AVG(amount) AS average, MIN(amount) AS min, MAX(amount) AS max
+            FROM (
+                SELECT CASE WHEN $4 AND entry_type = 'expense' THEN -amount ELSE amount END AS amount
+                FROM expenses
+                WHERE budgetid = $1
+                  AND NOT draft
+                  AND ($4 OR entry_type = 'expense')
+                  AND ($2::DATE IS NULL OR date >= $2)
+                  AND ($3::DATE IS NULL OR date <= $3)
+            ) e
+            "#,
+            query.budgetid,
+            query.start_date,
+            query.end_date,
+            query.signed
+        )
             .fetch_one(&pool)
             .await
-            .map_err(|e| warp::reject::custom(ServiceError::DatabaseError(e)))?;
+            .map_err(db_rejection)?;
 
-        if !user_owns_budget(claims.user_id, expense.budgetid, &pool, ServiceError::Unauthorized).await? {
+        let budget = sqlx::query!("SELECT settings FROM budgets WHERE id = $1", query.budgetid)
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+        let (currency, _) = budget_currency(&budget.settings);
+        stats.average = stats.average.map(|average| round_amount(&average, currency));
+
+        Ok(warp::reply::with_status(warp::reply::json(&stats), StatusCode::OK))
+    }
+
+    /// Committed spend so far in the current `period` (`week`, `month` or `year`, default
+    /// `month`) next to the whole previous one, and the change between them in percent. Weeks
+    /// start on Monday, and periods follow UTC.
+    async fn handle_get_trend(claims: Claims, query: TrendQuery, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let period = query.period.as_deref().unwrap_or("month");
+        if !["week", "month", "year"].contains(&period) {
+            return Err(warp::reject::custom(ServiceError::BadRequest("period must be one of week, month, year".into())));
+        }
+
+        if !user_owns_budget(claims.user_id, query.budgetid, &pool, ServiceError::Unauthorized).await? {
             return Ok(warp::reply::with_status(
                 warp::reply::json(&json!({"error": "Unauthorized"})),
                 StatusCode::UNAUTHORIZED,
             ));
         }
 
-        Ok(warp::reply::with_status(warp::reply::json(&expense), StatusCode::OK))
+        let record = sqlx::query!(
+            r#"
+            WITH bounds AS (
+                SELECT date_trunc($2, $3::DATE)::DATE AS current_start,
+                       (date_trunc($2, $3::DATE) - ('1 ' || $2)::INTERVAL)::DATE AS previous_start
+            )
+            SELECT bounds.current_start AS "current_start!",
+                   bounds.previous_start AS "previous_start!",
+                   COALESCE(SUM(CASE WHEN $4 AND e.entry_type = 'expense' THEN -e.amount ELSE e.amount END) FILTER (WHERE e.date >= bounds.current_start), 0) AS "current!",
+                   COALESCE(SUM(CASE WHEN $4 AND e.entry_type = 'expense' THEN -e.amount ELSE e.amount END) FILTER (WHERE e.date < bounds.current_start), 0) AS "previous!",
+                   (SELECT settings FROM budgets WHERE id = $1) AS "settings!"
+            FROM bounds
+            LEFT JOIN expenses e ON e.budgetid = $1
+              AND NOT e.draft
+              AND ($4 OR e.entry_type = 'expense')
+              AND e.date >= bounds.previous_start
+              AND e.date <= $3
+            GROUP BY bounds.current_start, bounds.previous_start
+            "#,
+            query.budgetid,
+            period,
+            OffsetDateTime::now_utc().date(),
+            query.signed
+        )
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        let (currency, _) = budget_currency(&record.settings);
+        let change_percent = if record.previous.is_positive() {
+            Some(((&record.current - &record.previous) * BigDecimal::from(100) / &record.previous).round(2))
+        } else {
+            None
+        };
+
+        let trend = SpendingTrend {
+            period: period.to_string(),
+            current_start: record.current_start,
+            previous_start: record.previous_start,
+            current: round_amount(&record.current, currency),
+            previous: round_amount(&record.previous, currency),
+            change_percent,
+        };
+
+        Ok(warp::reply::with_status(warp::reply::json(&trend), StatusCode::OK))
+    }
+
+    /// Spend per month, keyed by the first of the month. With `year`, all twelve months of that year
+    /// are returned and empty ones report 0; otherwise only months with spending are listed.
+    async fn handle_get_monthly(claims: Claims, query: MonthlyQuery, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let bounds = match query.year {
+            Some(year) => {
+                let start = Date::from_calendar_date(year, Month::January, 1);
+                let end = Date::from_calendar_date(year, Month::December, 31);
+                match (start, end) {
+                    (Ok(start), Ok(end)) => Some((start, end)),
+                    _ => return Err(warp::reject::custom(ServiceError::BadRequest("year is out of range".into()))),
+                }
+            },
+            None => None,
+        };
+
+        if !user_owns_budget(claims.user_id, query.budgetid, &pool, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let totals = sqlx::query_as!(
+            MonthlyTotal,
+            r#"
+            SELECT date_trunc('month', date)::DATE AS "month!", SUM(CASE WHEN $4 AND entry_type = 'expense' THEN -amount ELSE amount END) AS "total!"
+            FROM expenses
+            WHERE budgetid = $1
+              AND NOT draft
+              AND ($4 OR entry_type = 'expense')
+              AND ($2::DATE IS NULL OR date BETWEEN $2 AND $3)
+            GROUP BY 1
+            ORDER BY 1
+            "#,
+            query.budgetid,
+            bounds.map(|(start, _)| start),
+            bounds.map(|(_, end)| end),
+            query.signed
+        )
+            .fetch_all(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        let totals = match query.year {
+            Some(year) => {
+                let mut month = Month::January;
+                let mut filled = Vec::with_capacity(12);
+                for _ in 0..12 {
+                    let first = Date::from_calendar_date(year, month, 1).expect("year was validated above");
+                    let total = totals
+                        .iter()
+                        .find(|total| total.month == first)
+                        .map_or_else(|| BigDecimal::from(0), |total| total.total.clone());
+                    filled.push(MonthlyTotal { month: first, total });
+                    month = month.next();
+                }
+                filled
+            },
+            None => totals,
+        };
+
+        Ok(warp::reply::with_status(warp::reply::json(&totals), StatusCode::OK))
+    }
+
+    /// Every entry in the budget as a CSV attachment, oldest first, optionally limited to a date
+    /// window. Income and drafts are included and told apart by the `entry_type` and `draft` columns.
+    async fn handle_export_expenses(claims: Claims, query: ExportQuery, pool: sqlx::PgPool) -> Result<warp::reply::Response, warp::Rejection> {
+        if let Some(start_date) = query.start_date {
+            validate_date_range(start_date, query.end_date)?;
+        }
+
+        if !user_owns_budget(claims.user_id, query.budgetid, &pool, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ).into_response());
+        }
+
+        let rows = sqlx::query!(
+            r#"
+            SELECT id, date, description, amount, entry_type, draft
+            FROM expenses
+            WHERE budgetid = $1
+              AND ($2::DATE IS NULL OR date >= $2)
+              AND ($3::DATE IS NULL OR date <= $3)
+            ORDER BY date, id
+            "#,
+            query.budgetid,
+            query.start_date,
+            query.end_date
+        )
+            .fetch_all(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        let mut csv = String::from("id,date,description,amount,entry_type,draft\r\n");
+        for row in rows {
+            csv.push_str(&format!(
+                "{},{},{},{},{},{}\r\n",
+                row.id,
+                row.date,
+                Self::csv_field(&row.description),
+                row.amount,
+                Self::csv_field(&row.entry_type),
+                row.draft
+            ));
+        }
+
+        let reply = warp::reply::with_header(csv, "Content-Type", "text/csv; charset=utf-8");
+        let reply = warp::reply::with_header(reply, "Content-Disposition", "attachment; filename=\"expenses.csv\"");
+        Ok(reply.into_response())
+    }
+
+    /// Quotes a CSV field when it contains a delimiter, quote or line break, doubling inner quotes.
+    /// A field a spreadsheet would read as a formula gets a leading `'` so it stays text.
+    fn csv_field(value: &str) -> String {
+        let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+            format!("'{}", value)
+        } else {
+            value.to_string()
+        };
+
+        if value.contains([',', '"', '\r', '\n']) {
+            format!("\"{}\"", value.replace('"', "\"\""))
+        } else {
+            value
+        }
     }
 
-    async fn handle_create_expense(claims: Claims, new_expense: NewExpense, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
-        if !user_owns_budget(claims.user_id, new_expense.budgetid, &pool, ServiceError::Unauthorized).await? {
+    async fn handle_get_expense(id: i32, claims: Claims, query: SignedQuery, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let expense = sqlx::query_as!(Expense, "SELECT * FROM expenses WHERE id = $1", id)
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        if !user_owns_budget(claims.user_id, expense.budgetid, &pool, ServiceError::Unauthorized).await? {
             return Ok(warp::reply::with_status(
                 warp::reply::json(&json!({"error": "Unauthorized"})),
                 StatusCode::UNAUTHORIZED,
             ));
         }
 
+        Ok(warp::reply::with_status(warp::reply::json(&expense.signed(query.signed)), StatusCode::OK))
+    }
+
+    /// The latest date an expense may carry: a day ahead of UTC, which leaves room for clients in
+    /// later time zones.
+    fn latest_date() -> Date {
+        OffsetDateTime::now_utc().date() + Duration::days(1)
+    }
+
+    /// Amounts must be positive (income is marked by `entry_type`, not a sign) and dates can be at
+    /// most a day ahead of UTC, which leaves room for clients in later time zones. A receipt is
+    /// referenced by an http(s) URL.
+    fn validate(new_expense: &NewExpense) -> Result<(), warp::Rejection> {
+        if !new_expense.amount.is_positive() {
+            return Err(warp::reject::custom(ServiceError::BadRequest("amount must be greater than zero".into())));
+        }
+
+        let latest = Self::latest_date();
+        if new_expense.date > latest {
+            return Err(warp::reject::custom(ServiceError::BadRequest(format!("date must not be later than {}", latest))));
+        }
+
+        if let Some(receipt_url) = &new_expense.receipt_url {
+            validate_http_url("receipt_url", receipt_url).map_err(|e| warp::reject::custom(ServiceError::BadRequest(e)))?;
+        }
+
+        Ok(())
+    }
+
+    /// With an `Idempotency-Key` header, a retry carrying the same key within
+    /// `IDEMPOTENCY_WINDOW_HOURS` gets the originally created expense back with the same 201
+    /// instead of a duplicate. Keys are scoped to the caller, and the retry's body is ignored.
+    /// A budget with a `webhook_url` setting is sent the new expense once it is committed.
+    async fn handle_create_expense(claims: Claims, idempotency_key: Option<String>, mut new_expense: NewExpense, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        Self::validate(&new_expense)?;
+        if idempotency_key.as_ref().is_some_and(|key| key.is_empty() || key.len() > 255) {
+            return Err(warp::reject::custom(ServiceError::BadRequest("Idempotency-Key must be between 1 and 255 characters".into())));
+        }
+
+        let mut tx = begin(&pool).await?;
+
+        match budget_lock_state(claims.user_id, new_expense.budgetid, &mut *tx).await? {
+            None => return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            )),
+            Some(true) => return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Budget is locked"})),
+                StatusCode::LOCKED,
+            )),
+            Some(false) => {},
+        }
+
+        let settings = Self::check_against_budget(&mut new_expense, &mut *tx).await?;
+
+        if let Some(key) = &idempotency_key {
+            if let Some(expense) = Self::claim_idempotency_key(claims.user_id, key, &mut tx).await? {
+                return Ok(warp::reply::with_status(warp::reply::json(&expense), StatusCode::CREATED));
+            }
+        }
+
         let expense = sqlx::query_as!(
             Expense,
-            "INSERT INTO expenses (budgetid, date, description, amount) VALUES ($1, $2, $3, $4) RETURNING id, budgetid, date, description, amount",
+            "INSERT INTO expenses (budgetid, date, description, amount, draft, custom_data, created_by, category, entry_type, receipt_url) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) RETURNING id, budgetid, date, description, amount, draft, custom_data, reimbursed, reimbursed_at, created_by, category, entry_type, receipt_url",
             new_expense.budgetid,
             new_expense.date,
             new_expense.description,
-            new_expense.amount
+            new_expense.amount,
+            new_expense.draft,
+            new_expense.custom_data,
+            claims.user_id,
+            new_expense.category,
+            new_expense.entry_type.as_str(),
+            new_expense.receipt_url
         )
-            .fetch_one(&pool)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(db_rejection)?;
+
+        if let Some(key) = &idempotency_key {
+            sqlx::query!(
+                "UPDATE idempotency_keys SET expense_id = $1 WHERE user_id = $2 AND key = $3",
+                expense.id,
+                claims.user_id,
+                key
+            )
+                .execute(&mut *tx)
+                .await
+                .map_err(db_rejection)?;
+        }
+
+        tx.commit()
             .await
-            .map_err(|e| warp::reject::custom(ServiceError::DatabaseError(e)))?;
+            .map_err(db_rejection)?;
+
+        if let Some(url) = settings.get("webhook_url").and_then(|url| url.as_str()) {
+            crate::webhooks::notify(url.to_string(), expense.budgetid, &expense);
+        }
 
         Ok(warp::reply::with_status(warp::reply::json(&expense), StatusCode::CREATED))
     }
 
-    async fn handle_update_expense(id: i32, claims: Claims, new_expense: NewExpense, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
-        if !user_owns_budget(claims.user_id, new_expense.budgetid, &pool, ServiceError::Unauthorized).await? {
-            return Ok(warp::reply::with_status(
-                warp::reply::json(&json!({"error": "Unauthorized"})),
-                StatusCode::UNAUTHORIZED,
-            ));
+    /// Records `key` for the caller, or returns the expense it already created. A concurrent
+    /// request with the same key waits on the insert until this transaction ends, so only one
+    /// of them creates an expense.
+    async fn claim_idempotency_key(
+        user_id: i32,
+        key: &str,
+        tx: &mut sqlx::Transaction<'static, sqlx::Postgres>,
+    ) -> Result<Option<Expense>, warp::Rejection> {
+        sqlx::query!(
+            "DELETE FROM idempotency_keys WHERE user_id = $1 AND key = $2 AND created_at < now() - make_interval(hours => $3)",
+            user_id,
+            key,
+            IDEMPOTENCY_WINDOW_HOURS
+        )
+            .execute(&mut **tx)
+            .await
+            .map_err(db_rejection)?;
+
+        let claimed = sqlx::query!(
+            "INSERT INTO idempotency_keys (user_id, key) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            user_id,
+            key
+        )
+            .execute(&mut **tx)
+            .await
+            .map_err(db_rejection)?;
+
+        if claimed.rows_affected() == 1 {
+            return Ok(None);
+        }
+
+        sqlx::query_as!(
+            Expense,
+            "SELECT e.* FROM expenses e JOIN idempotency_keys k ON k.expense_id = e.id WHERE k.user_id = $1 AND k.key = $2",
+            user_id,
+            key
+        )
+            .fetch_optional(&mut **tx)
+            .await
+            .map_err(db_rejection)
+    }
+
+    /// Drops idempotency keys older than the replay window.
+    pub async fn purge_idempotency_keys(pool: &sqlx::PgPool) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query!(
+            "DELETE FROM idempotency_keys WHERE created_at < now() - make_interval(hours => $1)",
+            IDEMPOTENCY_WINDOW_HOURS
+        )
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn handle_update_expense(id: i32, claims: Claims, mut new_expense: NewExpense, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        Self::validate(&new_expense)?;
+
+        let mut tx = begin(&pool).await?;
+
+        let existing = sqlx::query_as!(Expense, "SELECT * FROM expenses WHERE id = $1 FOR UPDATE", id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(db_rejection)?;
+
+        // Both the budget the expense is in and the one it moves to must be writable.
+        for budgetid in [existing.budgetid, new_expense.budgetid] {
+            match budget_lock_state(claims.user_id, budgetid, &mut *tx).await? {
+                None => return Ok(warp::reply::with_status(
+                    warp::reply::json(&json!({"error": "Unauthorized"})),
+                    StatusCode::UNAUTHORIZED,
+                )),
+                Some(true) => return Ok(warp::reply::with_status(
+                    warp::reply::json(&json!({"error": "Budget is locked"})),
+                    StatusCode::LOCKED,
+                )),
+                Some(false) => {},
+            }
         }
 
+        Self::check_against_budget(&mut new_expense, &mut *tx).await?;
+
         let expense = sqlx::query_as!(
             Expense,
-            "UPDATE expenses SET budgetid = $1, date = $2, description = $3, amount = $4 WHERE id = $5 RETURNING id, budgetid, date, description, amount",
+            "UPDATE expenses SET budgetid = $1, date = $2, description = $3, amount = $4, draft = $5, custom_data = $6, category = $7, entry_type = $8, receipt_url = $9 WHERE id = $10 RETURNING id, budgetid, date, description, amount, draft, custom_data, reimbursed, reimbursed_at, created_by, category, entry_type, receipt_url",
             new_expense.budgetid,
             new_expense.date,
             new_expense.description,
             new_expense.amount,
+            new_expense.draft,
+            new_expense.custom_data,
+            new_expense.category,
+            new_expense.entry_type.as_str(),
+            new_expense.receipt_url,
+            id
+        )
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(db_rejection)?;
+
+        tx.commit()
+            .await
+            .map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&expense), StatusCode::OK))
+    }
+
+    /// Checks `custom_data` against the target budget's custom fields and rounds the amount to
+    /// the budget currency's scale. Returns the budget's settings.
+    async fn check_against_budget<'c, X>(new_expense: &mut NewExpense, executor: X) -> Result<serde_json::Value, warp::Rejection>
+    where
+        X: sqlx::PgExecutor<'c>,
+    {
+        let budget = sqlx::query!("SELECT settings FROM budgets WHERE id = $1", new_expense.budgetid)
+            .fetch_one(executor)
+            .await
+            .map_err(db_rejection)?;
+
+        if let Some(custom_data) = &new_expense.custom_data {
+            validate_custom_data(&budget.settings, custom_data).map_err(|e| warp::reject::custom(ServiceError::BadRequest(e)))?;
+        }
+
+        let (currency, _) = budget_currency(&budget.settings);
+        new_expense.amount = round_amount(&new_expense.amount, currency);
+        if !new_expense.amount.is_positive() {
+            return Err(warp::reject::custom(ServiceError::BadRequest(format!("amount rounds to zero in {}", currency))));
+        }
+
+        Ok(budget.settings)
+    }
+
+    async fn handle_commit_expense(id: i32, claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let expense = sqlx::query_as!(Expense, "SELECT * FROM expenses WHERE id = $1", id)
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        match budget_lock_state(claims.user_id, expense.budgetid, &pool).await? {
+            None => return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            )),
+            Some(true) => return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Budget is locked"})),
+                StatusCode::LOCKED,
+            )),
+            Some(false) => {},
+        }
+
+        let expense = sqlx::query_as!(
+            Expense,
+            "UPDATE expenses SET draft = false WHERE id = $1 RETURNING id, budgetid, date, description, amount, draft, custom_data, reimbursed, reimbursed_at, created_by, category, entry_type, receipt_url",
+            id
+        )
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&expense), StatusCode::OK))
+    }
+
+    /// Toggles whether an expense has been reimbursed, stamping or clearing `reimbursed_at`.
+    async fn handle_reimburse_expense(id: i32, claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let expense = sqlx::query_as!(Expense, "SELECT * FROM expenses WHERE id = $1", id)
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        match budget_lock_state(claims.user_id, expense.budgetid, &pool).await? {
+            None => return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            )),
+            Some(true) => return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Budget is locked"})),
+                StatusCode::LOCKED,
+            )),
+            Some(false) => {},
+        }
+
+        let expense = sqlx::query_as!(
+            Expense,
+            "UPDATE expenses
+             SET reimbursed = NOT reimbursed,
+                 reimbursed_at = CASE WHEN reimbursed THEN NULL ELSE CURRENT_DATE END
+             WHERE id = $1
+             RETURNING id, budgetid, date, description, amount, draft, custom_data, reimbursed, reimbursed_at, created_by, category, entry_type, receipt_url",
             id
         )
             .fetch_one(&pool)
             .await
-            .map_err(|e| warp::reject::custom(ServiceError::DatabaseError(e)))?;
+            .map_err(db_rejection)?;
 
         Ok(warp::reply::with_status(warp::reply::json(&expense), StatusCode::OK))
     }
 
+    /// Moves the budget's expenses (or only `ids`, if given) by `days`, at most ten years either
+    /// way, and returns how many changed. Nothing moves if any shifted date would break the
+    /// date rule for expenses.
+    async fn handle_shift_dates(claims: Claims, shift: ShiftDates, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        if !(-MAX_SHIFT_DAYS..=MAX_SHIFT_DAYS).contains(&shift.days) {
+            return Err(warp::reject::custom(ServiceError::BadRequest(format!(
+                "days must be between -{} and {}",
+                MAX_SHIFT_DAYS, MAX_SHIFT_DAYS
+            ))));
+        }
+
+        let mut tx = begin(&pool).await?;
+
+        match budget_lock_state(claims.user_id, shift.budgetid, &mut *tx).await? {
+            None => return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            )),
+            Some(true) => return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Budget is locked"})),
+                StatusCode::LOCKED,
+            )),
+            Some(false) => {},
+        }
+
+        let shifted = sqlx::query_scalar!(
+            "UPDATE expenses SET date = date + $3::INTEGER WHERE budgetid = $1 AND ($2::INTEGER[] IS NULL OR id = ANY($2))
+             RETURNING date",
+            shift.budgetid,
+            shift.ids.as_deref(),
+            shift.days
+        )
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(db_rejection)?;
+
+        let latest = Self::latest_date();
+        if shifted.iter().any(|date| *date > latest) {
+            return Err(warp::reject::custom(ServiceError::BadRequest(format!(
+                "shifted dates must not be later than {}",
+                latest
+            ))));
+        }
+
+        tx.commit().await.map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&json!({"updated": shifted.len()})), StatusCode::OK))
+    }
+
+    /// Expenses in any of the caller's budgets whose description contains the words in `q`,
+    /// matched after English stemming so "flight" also finds "Flights". Newest first and paged
+    /// like `GET /expenses`: by `limit`/`offset` with `X-Total-Count`, or by the `after` cursor
+    /// with `X-Next-Cursor`.
+    async fn handle_search_expenses(claims: Claims, query: SearchQuery, pool: sqlx::PgPool) -> Result<warp::reply::Response, warp::Rejection> {
+        let q = query.q.trim();
+        if q.is_empty() {
+            return Err(warp::reject::custom(ServiceError::BadRequest("q must not be empty".into())));
+        }
+        let limit = query.limit.unwrap_or(50).clamp(1, 200);
+        let offset = query.offset.unwrap_or(0);
+        if offset < 0 {
+            return Err(warp::reject::custom(ServiceError::BadRequest("offset must not be negative".into())));
+        }
+        if query.after.is_some() && query.offset.is_some() {
+            return Err(warp::reject::custom(ServiceError::BadRequest("after cannot be combined with offset".into())));
+        }
+
+        let cursor = match query.after {
+            Some(after) => {
+                let date = sqlx::query_scalar!(
+                    "SELECT e.date FROM expenses e JOIN user_budgets ub ON ub.budgetid = e.budgetid AND ub.userid = $2 WHERE e.id = $1",
+                    after,
+                    claims.user_id
+                )
+                    .fetch_optional(&pool)
+                    .await
+                    .map_err(db_rejection)?
+                    .ok_or_else(|| warp::reject::custom(ServiceError::BadRequest("after must be the id of one of your expenses".into())))?;
+                Some((date, after))
+            },
+            None => None,
+        };
+
+        // plainto_tsquery treats the input as plain words, so operators in it have no effect.
+        let total = sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*) AS "count!"
+            FROM expenses e
+            JOIN user_budgets ub ON ub.budgetid = e.budgetid AND ub.userid = $1
+            WHERE to_tsvector('english', e.description) @@ plainto_tsquery('english', $2)
+            "#,
+            claims.user_id,
+            q
+        )
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        let results: Vec<SearchResult> = sqlx::query!(
+            r#"
+            SELECT e.id, e.budgetid, e.date, e.description, e.amount, e.draft, e.custom_data, e.reimbursed, e.reimbursed_at, e.created_by, e.category, e.entry_type, e.receipt_url, b.name AS budget_name
+            FROM expenses e
+            JOIN user_budgets ub ON ub.budgetid = e.budgetid AND ub.userid = $1
+            JOIN budgets b ON b.id = e.budgetid
+            WHERE to_tsvector('english', e.description) @@ plainto_tsquery('english', $2)
+              AND ($5::DATE IS NULL OR (e.date, e.id) < ($5, $6))
+            ORDER BY e.date DESC, e.id DESC
+            LIMIT $3 OFFSET $4
+            "#,
+            claims.user_id,
+            q,
+            limit,
+            offset,
+            cursor.map(|(date, _)| date),
+            cursor.map(|(_, id)| id)
+        )
+            .fetch_all(&pool)
+            .await
+            .map_err(db_rejection)?
+            .into_iter()
+            .map(|record| SearchResult {
+                expense: Expense {
+                    id: record.id,
+                    budgetid: record.budgetid,
+                    date: record.date,
+                    description: record.description,
+                    amount: record.amount,
+                    draft: record.draft,
+                    custom_data: record.custom_data,
+                    reimbursed: record.reimbursed,
+                    reimbursed_at: record.reimbursed_at,
+                    created_by: record.created_by,
+                    category: record.category,
+                    entry_type: record.entry_type,
+                    receipt_url: record.receipt_url,
+                }.signed(query.signed),
+                budget_name: record.budget_name,
+            })
+            .collect();
+
+        let next_cursor = match results.last() {
+            Some(last) if results.len() as i64 == limit => Some(last.expense.id),
+            _ => None,
+        };
+
+        let mut response = warp::reply::with_header(
+            warp::reply::with_status(warp::reply::json(&results), StatusCode::OK),
+            "X-Total-Count",
+            total.to_string(),
+        ).into_response();
+        if let Some(next_cursor) = next_cursor {
+            response.headers_mut().insert("X-Next-Cursor", next_cursor.into());
+        }
+
+        Ok(response)
+    }
+
+    /// Finds expenses within `tolerance` of `amount` across the caller's budgets, grouped by budget.
+    async fn handle_find_expenses(claims: Claims, query: FindQuery, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let tolerance = query.tolerance.unwrap_or_else(|| BigDecimal::from(0));
+        if tolerance.is_negative() {
+            return Err(warp::reject::custom(ServiceError::BadRequest("tolerance must not be negative".into())));
+        }
+
+        let budget_ids = match query.budgetids.as_deref() {
+            Some(ids) => {
+                let ids = parse_id_list(ids)?;
+                let owned = user_owns_budgets(claims.user_id, &ids, &pool).await?;
+                if ids.iter().any(|id| !owned.contains(id)) {
+                    return Ok(warp::reply::with_status(
+                        warp::reply::json(&json!({"error": "Unauthorized"})),
+                        StatusCode::UNAUTHORIZED,
+                    ));
+                }
+                Some(ids)
+            },
+            None => None,
+        };
+
+        let records = sqlx::query!(
+            r#"
+            SELECT e.id, e.budgetid, e.date, e.description, e.amount, e.draft, e.custom_data, e.reimbursed, e.reimbursed_at, e.created_by, e.category, e.entry_type, e.receipt_url, b.name AS budget_name
+            FROM expenses e
+            JOIN user_budgets ub ON ub.budgetid = e.budgetid AND ub.userid = $1
+            JOIN budgets b ON b.id = e.budgetid
+            WHERE e.amount BETWEEN $2::NUMERIC - $3::NUMERIC AND $2::NUMERIC + $3::NUMERIC
+              AND ($4::INTEGER[] IS NULL OR e.budgetid = ANY($4))
+            ORDER BY b.id, e.date DESC, e.id DESC
+            "#,
+            claims.user_id,
+            query.amount,
+            tolerance,
+            budget_ids.as_deref()
+        )
+            .fetch_all(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        let mut matches: Vec<BudgetMatches> = Vec::new();
+        for record in records {
+            let expense = Expense {
+                id: record.id,
+                budgetid: record.budgetid,
+                date: record.date,
+                description: record.description,
+                amount: record.amount,
+                draft: record.draft,
+                custom_data: record.custom_data,
+                reimbursed: record.reimbursed,
+                reimbursed_at: record.reimbursed_at,
+                created_by: record.created_by,
+                category: record.category,
+                entry_type: record.entry_type,
+                receipt_url: record.receipt_url,
+            };
+
+            match matches.last_mut() {
+                Some(group) if group.budgetid == expense.budgetid => group.expenses.push(expense),
+                _ => matches.push(BudgetMatches {
+                    budgetid: expense.budgetid,
+                    budget_name: record.budget_name,
+                    expenses: vec![expense],
+                }),
+            }
+        }
+
+        Ok(warp::reply::with_status(warp::reply::json(&matches), StatusCode::OK))
+    }
+
     async fn handle_delete_expense(id: i32, claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
         let expense = sqlx::query_as!(Expense, "SELECT * FROM expenses WHERE id = $1", id)
             .fetch_one(&pool)
             .await
-            .map_err(|e| warp::reject::custom(ServiceError::DatabaseError(e)))?;
+            .map_err(db_rejection)?;
 
-        if !user_owns_budget(claims.user_id, expense.budgetid, &pool, ServiceError::Unauthorized).await? {
-            return Ok(warp::reply::with_status(
+        match budget_lock_state(claims.user_id, expense.budgetid, &pool).await? {
+            None => return Ok(warp::reply::with_status(
                 warp::reply::json(&json!({"error": "Unauthorized"})),
                 StatusCode::UNAUTHORIZED,
-            ));
+            )),
+            Some(true) => return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Budget is locked"})),
+                StatusCode::LOCKED,
+            )),
+            Some(false) => {},
         }
 
         sqlx::query!("DELETE FROM expenses WHERE id = $1", id)
             .execute(&pool)
             .await
-            .map_err(|e| warp::reject::custom(ServiceError::DatabaseError(e)))?;
+            .map_err(db_rejection)?;
 
         Ok(warp::reply::with_status(warp::reply::json(&format!("Expense with id {} deleted", id)), StatusCode::OK))
     }
 
+    /// Deletes every listed expense or none of them: one the caller can't access rejects the
+    /// batch with 401, one in a locked budget with 423. Ids that don't exist are skipped, so the
+    /// returned count may be lower than the number of ids.
+    async fn handle_delete_expenses(claims: Claims, delete: DeleteExpenses, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        if delete.ids.is_empty() {
+            return Err(warp::reject::custom(ServiceError::BadRequest("ids must not be empty".into())));
+        }
+
+        let mut tx = begin(&pool).await?;
+
+        let targets = sqlx::query!(
+            r#"
+            SELECT e.id, b.locked,
+                   EXISTS (SELECT 1 FROM user_budgets ub WHERE ub.budgetid = e.budgetid AND ub.userid = $1) AS "member!"
+            FROM expenses e
+            JOIN budgets b ON b.id = e.budgetid
+            WHERE e.id = ANY($2)
+            FOR UPDATE OF e FOR SHARE OF b
+            "#,
+            claims.user_id,
+            &delete.ids
+        )
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(db_rejection)?;
+
+        if targets.iter().any(|target| !target.member) {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+        if targets.iter().any(|target| target.locked) {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Budget is locked"})),
+                StatusCode::LOCKED,
+            ));
+        }
+
+        let result = sqlx::query!("DELETE FROM expenses WHERE id = ANY($1)", &delete.ids)
+            .execute(&mut *tx)
+            .await
+            .map_err(db_rejection)?;
+
+        tx.commit()
+            .await
+            .map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&json!({"deleted": result.rows_affected()})), StatusCode::OK))
+    }
+
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use crate::utils::handle_rejection;
+
+    async fn dates(pool: &sqlx::PgPool, ids: &[i32]) -> Vec<Date> {
+        let mut dates = Vec::new();
+        for id in ids {
+            dates.push(sqlx::query_scalar("SELECT date FROM expenses WHERE id = $1").bind(id).fetch_one(pool).await.unwrap());
+        }
+        dates
+    }
+
+    #[sqlx::test]
+    async fn shift_dates_moves_selected_expenses_by_a_day(pool: sqlx::PgPool) {
+        let user = test_support::user(&pool, "owner").await;
+        let budget = test_support::budget(&pool, user).await;
+        let first = test_support::expense(&pool, budget, "2024-02-28", "1.00").await;
+        let second = test_support::expense(&pool, budget, "2024-02-29", "2.00").await;
+        let third = test_support::expense(&pool, budget, "2024-12-31", "3.00").await;
+        let untouched = test_support::expense(&pool, budget, "2024-06-01", "4.00").await;
+        let routes = ExpenseService::new(pool.clone()).routes().recover(handle_rejection);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/expenses/shift-dates")
+            .header("authorization", test_support::bearer(user))
+            .json(&json!({"budgetid": budget, "ids": [first, second, third], "days": 1}))
+            .reply(&routes)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), r#"{"updated":3}"#);
+        assert_eq!(
+            dates(&pool, &[first, second, third, untouched]).await,
+            vec![
+                Date::from_calendar_date(2024, Month::February, 29).unwrap(),
+                Date::from_calendar_date(2024, Month::March, 1).unwrap(),
+                Date::from_calendar_date(2025, Month::January, 1).unwrap(),
+                Date::from_calendar_date(2024, Month::June, 1).unwrap(),
+            ]
+        );
+    }
+
+    #[sqlx::test]
+    async fn shift_dates_rejects_dates_past_tomorrow_and_changes_nothing(pool: sqlx::PgPool) {
+        let user = test_support::user(&pool, "owner").await;
+        let budget = test_support::budget(&pool, user).await;
+        let today = OffsetDateTime::now_utc().date();
+        let old = test_support::expense(&pool, budget, "2024-01-01", "1.00").await;
+        let recent = test_support::expense(&pool, budget, &today.to_string(), "2.00").await;
+        let routes = ExpenseService::new(pool.clone()).routes().recover(handle_rejection);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/expenses/shift-dates")
+            .header("authorization", test_support::bearer(user))
+            .json(&json!({"budgetid": budget, "days": 2}))
+            .reply(&routes)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(dates(&pool, &[old, recent]).await, vec![Date::from_calendar_date(2024, Month::January, 1).unwrap(), today]);
+    }
+
+    #[sqlx::test]
+    async fn shift_dates_bounds_days(pool: sqlx::PgPool) {
+        let user = test_support::user(&pool, "owner").await;
+        let budget = test_support::budget(&pool, user).await;
+        let routes = ExpenseService::new(pool.clone()).routes().recover(handle_rejection);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/expenses/shift-dates")
+            .header("authorization", test_support::bearer(user))
+            .json(&json!({"budgetid": budget, "days": -(MAX_SHIFT_DAYS + 1)}))
+            .reply(&routes)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[sqlx::test]
+    async fn get_expenses_rejects_reversed_dates(pool: sqlx::PgPool) {
+        let user = test_support::user(&pool, "owner").await;
+        let budget = test_support::budget(&pool, user).await;
+        let routes = ExpenseService::new(pool.clone()).routes().recover(handle_rejection);
+
+        let response = warp::test::request()
+            .path(&format!("/expenses?budgetid={}&start_date=2024-03-01&end_date=2024-01-01", budget))
+            .header("authorization", test_support::bearer(user))
+            .reply(&routes)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[sqlx::test]
+    async fn create_expense_rejects_locked_budget(pool: sqlx::PgPool) {
+        let user = test_support::user(&pool, "owner").await;
+        let budget = test_support::budget(&pool, user).await;
+        sqlx::query("UPDATE budgets SET locked = TRUE WHERE id = $1").bind(budget).execute(&pool).await.unwrap();
+        let routes = ExpenseService::new(pool.clone()).routes().recover(handle_rejection);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/expenses")
+            .header("authorization", test_support::bearer(user))
+            .json(&json!({"budgetid": budget, "date": OffsetDateTime::now_utc().date(), "description": "Coffee", "amount": "3.50"}))
+            .reply(&routes)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::LOCKED);
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM expenses WHERE budgetid = $1").bind(budget).fetch_one(&pool).await.unwrap();
+        assert_eq!(count, 0);
+    }
+
+    async fn get_json(routes: &(impl Filter<Extract = (impl warp::Reply,), Error = std::convert::Infallible> + Clone + 'static), user: i32, path: &str) -> serde_json::Value {
+        let response = warp::test::request().path(path).header("authorization", test_support::bearer(user)).reply(routes).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", path);
+        serde_json::from_slice(response.body()).unwrap()
+    }
+
+    fn number(value: &serde_json::Value) -> f64 {
+        value.as_str().unwrap().parse().unwrap()
+    }
+
+    #[sqlx::test]
+    async fn stats_round_the_average_to_the_budget_currency(pool: sqlx::PgPool) {
+        let user = test_support::user(&pool, "owner").await;
+        let routes = ExpenseService::new(pool.clone()).routes().recover(handle_rejection);
+
+        for (currency, amounts, average) in [("USD", ["10.00", "10.00", "10.01"], "10.00"), ("JPY", ["100", "100", "101"], "100")] {
+            let budget = test_support::budget(&pool, user).await;
+            sqlx::query("UPDATE budgets SET settings = jsonb_build_object('currency', $2::TEXT) WHERE id = $1")
+                .bind(budget)
+                .bind(currency)
+                .execute(&pool)
+                .await
+                .unwrap();
+            for amount in amounts {
+                test_support::expense(&pool, budget, "2024-03-01", amount).await;
+            }
+
+            let stats = get_json(&routes, user, &format!("/expenses/stats?budgetid={}", budget)).await;
+            assert_eq!(stats["average"], average, "{}", currency);
+        }
+    }
+
+    #[sqlx::test]
+    async fn signed_amounts_make_income_positive_and_expenses_negative(pool: sqlx::PgPool) {
+        let user = test_support::user(&pool, "owner").await;
+        let budget = test_support::budget(&pool, user).await;
+        let expense = test_support::expense(&pool, budget, "2024-03-01", "30.00").await;
+        sqlx::query("INSERT INTO expenses (budgetid, date, description, amount, entry_type) VALUES ($1, '2024-03-02', 'Salary', 100, 'income')")
+            .bind(budget)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let routes = ExpenseService::new(pool.clone()).routes().recover(handle_rejection);
+
+        let total = get_json(&routes, user, &format!("/expenses/total?budgetid={}", budget)).await;
+        assert_eq!(number(&total["total"]), 30.0);
+        let total = get_json(&routes, user, &format!("/expenses/total?budgetid={}&signed=true", budget)).await;
+        assert_eq!(number(&total["total"]), 70.0);
+
+        let stats = get_json(&routes, user, &format!("/expenses/stats?budgetid={}", budget)).await;
+        assert_eq!((stats["count"].as_i64(), number(&stats["total"])), (Some(1), 30.0));
+        let stats = get_json(&routes, user, &format!("/expenses/stats?budgetid={}&signed=true", budget)).await;
+        assert_eq!((stats["count"].as_i64(), number(&stats["total"]), number(&stats["min"])), (Some(2), 70.0, -30.0));
+
+        let path = format!("/expenses?budgetid={}&start_date=2024-01-01&running_balance=true", budget);
+        let rows = get_json(&routes, user, &path).await;
+        let amounts: Vec<(f64, f64)> = rows.as_array().unwrap().iter().map(|row| (number(&row["amount"]), number(&row["running_balance"]))).collect();
+        assert_eq!(amounts, vec![(100.0, 30.0), (30.0, 30.0)]);
+        let rows = get_json(&routes, user, &format!("{}&signed=true", path)).await;
+        let amounts: Vec<(f64, f64)> = rows.as_array().unwrap().iter().map(|row| (number(&row["amount"]), number(&row["running_balance"]))).collect();
+        assert_eq!(amounts, vec![(100.0, 70.0), (-30.0, -30.0)]);
+
+        let single = get_json(&routes, user, &format!("/expenses/{}", expense)).await;
+        assert_eq!(number(&single["amount"]), 30.0);
+        let single = get_json(&routes, user, &format!("/expenses/{}?signed=true", expense)).await;
+        assert_eq!(number(&single["amount"]), -30.0);
+    }
+
+    #[test]
+    fn csv_field_quotes_and_defuses_formulas() {
+        assert_eq!(ExpenseService::csv_field("Groceries"), "Groceries");
+        assert_eq!(ExpenseService::csv_field("Fish, chips"), "\"Fish, chips\"");
+        assert_eq!(ExpenseService::csv_field("The \"good\" one"), "\"The \"\"good\"\" one\"");
+        assert_eq!(ExpenseService::csv_field("=HYPERLINK(\"http://x\")"), "\"'=HYPERLINK(\"\"http://x\"\")\"");
+        assert_eq!(ExpenseService::csv_field("+1"), "'+1");
+        assert_eq!(ExpenseService::csv_field("-1"), "'-1");
+        assert_eq!(ExpenseService::csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(ExpenseService::csv_field("Tea @ noon"), "Tea @ noon");
+    }
+
+    #[sqlx::test]
+    async fn export_marks_income_and_drafts(pool: sqlx::PgPool) {
+        let user = test_support::user(&pool, "owner").await;
+        let budget = test_support::budget(&pool, user).await;
+        let expense = test_support::expense(&pool, budget, "2024-03-01", "12.50").await;
+        let income: i32 = sqlx::query_scalar(
+            "INSERT INTO expenses (budgetid, date, description, amount, entry_type, draft)
+             VALUES ($1, '2024-03-02', '=1+1', 100, 'income', true) RETURNING id",
+        )
+            .bind(budget)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        let routes = ExpenseService::new(pool.clone()).routes().recover(handle_rejection);
 
+        let response = warp::test::request()
+            .path(&format!("/expenses/export?budgetid={}", budget))
+            .header("authorization", test_support::bearer(user))
+            .reply(&routes)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            std::str::from_utf8(response.body()).unwrap(),
+            format!(
+                "id,date,description,amount,entry_type,draft\r\n{},2024-03-01,Test expense,12.5000,expense,false\r\n{},2024-03-02,'=1+1,100,income,true\r\n",
+                expense, income
+            )
+        );
+    }
 }
@@ -2,3 +2,5 @@ pub mod budgets;
 pub mod expenses;
 pub mod users;
 pub mod user_budgets;
+pub mod scheduled_expenses;
+pub mod recurring_expenses;
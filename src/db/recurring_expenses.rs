@@ -0,0 +1,458 @@
+use warp::{Filter, http::StatusCode};
+use crate::utils::{json_body, with_db, user_owns_budget, budget_lock_state, budget_currency, round_amount, db_rejection, ServiceError};
+use serde::{Deserialize, Serialize};
+use bigdecimal::{BigDecimal, Signed};
+use time::{Date, Duration, OffsetDateTime};
+use crate::auth::{with_auth, Claims};
+use serde_json::json;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RecurringExpense {
+    id: i32,
+    budgetid: i32,
+    description: String,
+    amount: BigDecimal,
+    cadence: String,
+    next_run: Date,
+    day_of_month: Option<i32>,
+    created_by: Option<i32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct NewRecurringExpense {
+    budgetid: i32,
+    description: String,
+    amount: BigDecimal,
+    cadence: Cadence,
+    next_run: Date,
+    day_of_month: Option<i32>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Cadence {
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+impl Cadence {
+    fn as_str(self) -> &'static str {
+        match self {
+            Cadence::Weekly => "weekly",
+            Cadence::Monthly => "monthly",
+            Cadence::Yearly => "yearly",
+        }
+    }
+}
+
+pub struct RecurringExpenseService {
+    pool: sqlx::PgPool,
+}
+
+impl RecurringExpenseService {
+    pub fn new(pool: sqlx::PgPool) -> Self {
+        RecurringExpenseService { pool }
+    }
+
+    pub fn routes(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let pool = self.pool.clone();
+
+        let get_budget_recurring = warp::path!("budgets" / i32 / "recurring")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_budget_recurring);
+
+        let get_recurring = warp::path!("recurring_expenses" / i32)
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_recurring);
+
+        let create_recurring = warp::path("recurring_expenses")
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(with_auth(pool.clone()))
+            .and(json_body())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_create_recurring);
+
+        let update_recurring = warp::path!("recurring_expenses" / i32)
+            .and(warp::put())
+            .and(with_auth(pool.clone()))
+            .and(json_body())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_update_recurring);
+
+        let delete_recurring = warp::path!("recurring_expenses" / i32)
+            .and(warp::delete())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_delete_recurring);
+
+        get_budget_recurring
+            .or(get_recurring)
+            .or(create_recurring)
+            .or(update_recurring)
+            .or(delete_recurring)
+    }
+
+    /// Records an expense for every run of a template that has come due, catching up on runs
+    /// missed while the job wasn't running, and moves `next_run` past today. Each template's
+    /// expenses and its new `next_run` are written together, so running twice in a day doesn't
+    /// record anything twice. Templates in locked or archived budgets wait, and catch up once
+    /// the budget is unlocked or restored. Amounts are rounded to the budget's currency.
+    pub async fn generate_due(pool: &sqlx::PgPool) -> Result<u64, sqlx::Error> {
+        let today = OffsetDateTime::now_utc().date();
+        let mut tx = pool.begin().await?;
+
+        let due = sqlx::query!(
+            "SELECT r.id, r.budgetid, r.description, r.amount, r.cadence, r.next_run, r.day_of_month, r.created_by, b.settings
+             FROM recurring_expenses r
+             JOIN budgets b ON b.id = r.budgetid
+             WHERE r.next_run <= $1 AND NOT b.locked AND NOT b.archived
+             ORDER BY r.id
+             FOR UPDATE OF r SKIP LOCKED",
+            today
+        )
+            .fetch_all(&mut *tx)
+            .await?;
+
+        let mut generated = 0;
+        for template in due {
+            let cadence = match template.cadence.as_str() {
+                "weekly" => Cadence::Weekly,
+                "monthly" => Cadence::Monthly,
+                _ => Cadence::Yearly,
+            };
+
+            let mut run = template.next_run;
+            let mut dates = Vec::new();
+            while run <= today {
+                dates.push(run);
+                run = match Self::advance(run, cadence, template.day_of_month) {
+                    Some(next) => next,
+                    None => break,
+                };
+            }
+            if run <= today {
+                log::warn!("Recurring expense {} has run out of dates", crate::logging::id(template.id));
+                continue;
+            }
+
+            let amount = round_amount(&template.amount, budget_currency(&template.settings).0);
+
+            let result = sqlx::query!(
+                "INSERT INTO expenses (budgetid, date, description, amount, created_by)
+                 SELECT $1, date, $2, $3, $4 FROM UNNEST($5::DATE[]) AS date",
+                template.budgetid,
+                template.description,
+                amount,
+                template.created_by,
+                &dates
+            )
+                .execute(&mut *tx)
+                .await?;
+
+            sqlx::query!("UPDATE recurring_expenses SET next_run = $1 WHERE id = $2", run, template.id)
+                .execute(&mut *tx)
+                .await?;
+
+            generated += result.rows_affected();
+        }
+
+        tx.commit().await?;
+
+        Ok(generated)
+    }
+
+    /// The run after `date`. Monthly and yearly runs land on `day_of_month`, or the last day
+    /// of the month when it is shorter.
+    fn advance(date: Date, cadence: Cadence, day_of_month: Option<i32>) -> Option<Date> {
+        let (year, month) = match cadence {
+            Cadence::Weekly => return date.checked_add(Duration::weeks(1)),
+            Cadence::Monthly if date.month() == time::Month::December => (date.year() + 1, time::Month::January),
+            Cadence::Monthly => (date.year(), date.month().next()),
+            Cadence::Yearly => (date.year() + 1, date.month()),
+        };
+
+        let day = day_of_month.map_or(date.day(), |day| day as u8);
+        let day = day.min(time::util::days_in_year_month(year, month));
+        Date::from_calendar_date(year, month, day).ok()
+    }
+
+    fn validate(new_recurring: &NewRecurringExpense) -> Result<(), warp::Rejection> {
+        if !new_recurring.amount.is_positive() {
+            return Err(warp::reject::custom(ServiceError::BadRequest("amount must be greater than zero".into())));
+        }
+
+        if new_recurring.next_run < OffsetDateTime::now_utc().date() {
+            return Err(warp::reject::custom(ServiceError::BadRequest("next_run must not be in the past".into())));
+        }
+
+        match new_recurring.day_of_month {
+            Some(_) if new_recurring.cadence == Cadence::Weekly => {
+                Err(warp::reject::custom(ServiceError::BadRequest("day_of_month does not apply to weekly expenses".into())))
+            },
+            Some(day) if !(1..=31).contains(&day) => {
+                Err(warp::reject::custom(ServiceError::BadRequest("day_of_month must be between 1 and 31".into())))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Rounds the amount to the target budget's currency, rejecting one that rounds to zero.
+    async fn round_to_budget(new_recurring: &mut NewRecurringExpense, pool: &sqlx::PgPool) -> Result<(), warp::Rejection> {
+        let budget = sqlx::query!("SELECT settings FROM budgets WHERE id = $1", new_recurring.budgetid)
+            .fetch_one(pool)
+            .await
+            .map_err(db_rejection)?;
+
+        let (currency, _) = budget_currency(&budget.settings);
+        new_recurring.amount = round_amount(&new_recurring.amount, currency);
+        if !new_recurring.amount.is_positive() {
+            return Err(warp::reject::custom(ServiceError::BadRequest(format!("amount rounds to zero in {}", currency))));
+        }
+
+        Ok(())
+    }
+
+    /// Monthly and yearly templates without a `day_of_month` keep the day `next_run` falls on.
+    fn day_of_month(new_recurring: &NewRecurringExpense) -> Option<i32> {
+        match new_recurring.cadence {
+            Cadence::Weekly => None,
+            _ => Some(new_recurring.day_of_month.unwrap_or(new_recurring.next_run.day() as i32)),
+        }
+    }
+
+    async fn handle_get_budget_recurring(id: i32, claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        if !user_owns_budget(claims.user_id, id, &pool, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let recurring = sqlx::query_as!(
+            RecurringExpense,
+            "SELECT * FROM recurring_expenses WHERE budgetid = $1 ORDER BY next_run, id",
+            id
+        )
+            .fetch_all(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&recurring), StatusCode::OK))
+    }
+
+    async fn handle_get_recurring(id: i32, claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let recurring = sqlx::query_as!(RecurringExpense, "SELECT * FROM recurring_expenses WHERE id = $1", id)
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        if !user_owns_budget(claims.user_id, recurring.budgetid, &pool, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        Ok(warp::reply::with_status(warp::reply::json(&recurring), StatusCode::OK))
+    }
+
+    async fn handle_create_recurring(claims: Claims, mut new_recurring: NewRecurringExpense, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        Self::validate(&new_recurring)?;
+
+        match budget_lock_state(claims.user_id, new_recurring.budgetid, &pool).await? {
+            None => return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            )),
+            Some(true) => return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Budget is locked"})),
+                StatusCode::LOCKED,
+            )),
+            Some(false) => {},
+        }
+
+        Self::round_to_budget(&mut new_recurring, &pool).await?;
+
+        let recurring = sqlx::query_as!(
+            RecurringExpense,
+            "INSERT INTO recurring_expenses (budgetid, description, amount, cadence, next_run, day_of_month, created_by)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)
+             RETURNING id, budgetid, description, amount, cadence, next_run, day_of_month, created_by",
+            new_recurring.budgetid,
+            new_recurring.description,
+            new_recurring.amount,
+            new_recurring.cadence.as_str(),
+            new_recurring.next_run,
+            Self::day_of_month(&new_recurring),
+            claims.user_id
+        )
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&recurring), StatusCode::CREATED))
+    }
+
+    async fn handle_update_recurring(id: i32, claims: Claims, mut new_recurring: NewRecurringExpense, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        Self::validate(&new_recurring)?;
+
+        let existing = sqlx::query_as!(RecurringExpense, "SELECT * FROM recurring_expenses WHERE id = $1", id)
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        // Both the budget the recurring expense is in and the one it moves to must be writable.
+        for budgetid in [existing.budgetid, new_recurring.budgetid] {
+            match budget_lock_state(claims.user_id, budgetid, &pool).await? {
+                None => return Ok(warp::reply::with_status(
+                    warp::reply::json(&json!({"error": "Unauthorized"})),
+                    StatusCode::UNAUTHORIZED,
+                )),
+                Some(true) => return Ok(warp::reply::with_status(
+                    warp::reply::json(&json!({"error": "Budget is locked"})),
+                    StatusCode::LOCKED,
+                )),
+                Some(false) => {},
+            }
+        }
+
+        Self::round_to_budget(&mut new_recurring, &pool).await?;
+
+        let recurring = sqlx::query_as!(
+            RecurringExpense,
+            "UPDATE recurring_expenses
+             SET budgetid = $1, description = $2, amount = $3, cadence = $4, next_run = $5, day_of_month = $6
+             WHERE id = $7
+             RETURNING id, budgetid, description, amount, cadence, next_run, day_of_month, created_by",
+            new_recurring.budgetid,
+            new_recurring.description,
+            new_recurring.amount,
+            new_recurring.cadence.as_str(),
+            new_recurring.next_run,
+            Self::day_of_month(&new_recurring),
+            id
+        )
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&recurring), StatusCode::OK))
+    }
+
+    async fn handle_delete_recurring(id: i32, claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let recurring = sqlx::query_as!(RecurringExpense, "SELECT * FROM recurring_expenses WHERE id = $1", id)
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        match budget_lock_state(claims.user_id, recurring.budgetid, &pool).await? {
+            None => return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            )),
+            Some(true) => return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Budget is locked"})),
+                StatusCode::LOCKED,
+            )),
+            Some(false) => {},
+        }
+
+        sqlx::query!("DELETE FROM recurring_expenses WHERE id = $1", id)
+            .execute(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&format!("Recurring expense with id {} deleted", id)), StatusCode::OK))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use crate::utils::handle_rejection;
+    use time::Month;
+
+    fn date(year: i32, month: Month, day: u8) -> Date {
+        Date::from_calendar_date(year, month, day).unwrap()
+    }
+
+    #[test]
+    fn advance_moves_weekly_runs_by_seven_days() {
+        assert_eq!(RecurringExpenseService::advance(date(2024, Month::December, 28), Cadence::Weekly, None), Some(date(2025, Month::January, 4)));
+    }
+
+    #[test]
+    fn advance_keeps_the_day_of_month_and_clamps_short_months() {
+        assert_eq!(RecurringExpenseService::advance(date(2024, Month::January, 31), Cadence::Monthly, None), Some(date(2024, Month::February, 29)));
+        assert_eq!(RecurringExpenseService::advance(date(2024, Month::February, 29), Cadence::Monthly, Some(31)), Some(date(2024, Month::March, 31)));
+        assert_eq!(RecurringExpenseService::advance(date(2024, Month::December, 15), Cadence::Monthly, None), Some(date(2025, Month::January, 15)));
+    }
+
+    #[test]
+    fn advance_moves_yearly_runs_and_clamps_leap_days() {
+        assert_eq!(RecurringExpenseService::advance(date(2024, Month::February, 29), Cadence::Yearly, None), Some(date(2025, Month::February, 28)));
+        assert_eq!(RecurringExpenseService::advance(date(2025, Month::February, 28), Cadence::Yearly, Some(29)), Some(date(2026, Month::February, 28)));
+    }
+
+    async fn yen_budget(pool: &sqlx::PgPool, user: i32) -> i32 {
+        let budget = test_support::budget(pool, user).await;
+        sqlx::query("UPDATE budgets SET settings = '{\"currency\": \"JPY\"}' WHERE id = $1")
+            .bind(budget)
+            .execute(pool)
+            .await
+            .unwrap();
+        budget
+    }
+
+    #[sqlx::test]
+    async fn create_recurring_rounds_to_the_budget_currency(pool: sqlx::PgPool) {
+        let user = test_support::user(&pool, "owner").await;
+        let budget = yen_budget(&pool, user).await;
+        let routes = RecurringExpenseService::new(pool.clone()).routes().recover(handle_rejection);
+
+        for (amount, status) in [("10.5", StatusCode::CREATED), ("0.4", StatusCode::BAD_REQUEST)] {
+            let response = warp::test::request()
+                .method("POST")
+                .path("/recurring_expenses")
+                .header("authorization", test_support::bearer(user))
+                .json(&json!({"budgetid": budget, "description": "Rent", "amount": amount, "cadence": "monthly", "next_run": OffsetDateTime::now_utc().date()}))
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), status, "{}", amount);
+        }
+
+        let amounts: Vec<f64> = sqlx::query_scalar("SELECT amount::FLOAT8 FROM recurring_expenses WHERE budgetid = $1")
+            .bind(budget)
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(amounts, vec![11.0]);
+    }
+
+    #[sqlx::test]
+    async fn generate_due_rounds_to_the_budget_currency(pool: sqlx::PgPool) {
+        let user = test_support::user(&pool, "owner").await;
+        let budget = yen_budget(&pool, user).await;
+        sqlx::query("INSERT INTO recurring_expenses (budgetid, description, amount, cadence, next_run) VALUES ($1, 'Rent', 10.5, 'weekly', CURRENT_DATE)")
+            .bind(budget)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(RecurringExpenseService::generate_due(&pool).await.unwrap(), 1);
+
+        let amount: f64 = sqlx::query_scalar("SELECT amount::FLOAT8 FROM expenses WHERE budgetid = $1")
+            .bind(budget)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(amount, 11.0);
+    }
+}
@@ -0,0 +1,318 @@
+use warp::{Filter, http::StatusCode};
+use crate::utils::{json_body, with_db, user_owns_budget, budget_lock_state, round_amount, budget_currency, db_rejection, ServiceError};
+use serde::{Deserialize, Serialize};
+use bigdecimal::{BigDecimal, Signed};
+use time::{Date, OffsetDateTime};
+use crate::auth::{with_auth, Claims};
+use serde_json::json;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ScheduledExpense {
+    id: i32,
+    budgetid: i32,
+    date: Date,
+    description: String,
+    amount: BigDecimal,
+    created_by: Option<i32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct NewScheduledExpense {
+    budgetid: i32,
+    date: Date,
+    description: String,
+    amount: BigDecimal,
+}
+
+pub struct ScheduledExpenseService {
+    pool: sqlx::PgPool,
+}
+
+impl ScheduledExpenseService {
+    pub fn new(pool: sqlx::PgPool) -> Self {
+        ScheduledExpenseService { pool }
+    }
+
+    pub fn routes(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let pool = self.pool.clone();
+
+        let get_budget_scheduled = warp::path!("budgets" / i32 / "scheduled")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_budget_scheduled);
+
+        let get_scheduled = warp::path!("scheduled_expenses" / i32)
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_scheduled);
+
+        let create_scheduled = warp::path("scheduled_expenses")
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(with_auth(pool.clone()))
+            .and(json_body())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_create_scheduled);
+
+        let update_scheduled = warp::path!("scheduled_expenses" / i32)
+            .and(warp::put())
+            .and(with_auth(pool.clone()))
+            .and(json_body())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_update_scheduled);
+
+        let delete_scheduled = warp::path!("scheduled_expenses" / i32)
+            .and(warp::delete())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_delete_scheduled);
+
+        get_budget_scheduled
+            .or(get_scheduled)
+            .or(create_scheduled)
+            .or(update_scheduled)
+            .or(delete_scheduled)
+    }
+
+    /// Turns every scheduled expense that has come due into a real expense, rounded to its
+    /// budget's currency, and removes the schedule in the same transaction so a repeated run
+    /// can't materialize anything twice. Schedules in locked or archived budgets are left
+    /// alone until the budget is unlocked or restored.
+    pub async fn materialize_due(pool: &sqlx::PgPool) -> Result<u64, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        let due = sqlx::query!(
+            "SELECT s.id, s.budgetid, s.date, s.description, s.amount, s.created_by, b.settings
+             FROM scheduled_expenses s
+             JOIN budgets b ON b.id = s.budgetid
+             WHERE s.date <= CURRENT_DATE AND NOT b.locked AND NOT b.archived
+             ORDER BY s.id
+             FOR UPDATE OF s SKIP LOCKED"
+        )
+            .fetch_all(&mut *tx)
+            .await?;
+
+        let mut materialized = 0;
+        for scheduled in due {
+            let amount = round_amount(&scheduled.amount, budget_currency(&scheduled.settings).0);
+
+            let result = sqlx::query!(
+                "INSERT INTO expenses (budgetid, date, description, amount, created_by) VALUES ($1, $2, $3, $4, $5)",
+                scheduled.budgetid,
+                scheduled.date,
+                scheduled.description,
+                amount,
+                scheduled.created_by
+            )
+                .execute(&mut *tx)
+                .await?;
+
+            sqlx::query!("DELETE FROM scheduled_expenses WHERE id = $1", scheduled.id)
+                .execute(&mut *tx)
+                .await?;
+
+            materialized += result.rows_affected();
+        }
+
+        tx.commit().await?;
+
+        Ok(materialized)
+    }
+
+    fn validate(new_scheduled: &NewScheduledExpense) -> Result<(), warp::Rejection> {
+        if !new_scheduled.amount.is_positive() {
+            return Err(warp::reject::custom(ServiceError::BadRequest("amount must be greater than zero".into())));
+        }
+
+        if new_scheduled.date < OffsetDateTime::now_utc().date() {
+            return Err(warp::reject::custom(ServiceError::BadRequest("date must not be in the past".into())));
+        }
+
+        Ok(())
+    }
+
+    async fn handle_get_budget_scheduled(id: i32, claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        if !user_owns_budget(claims.user_id, id, &pool, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let scheduled = sqlx::query_as!(
+            ScheduledExpense,
+            "SELECT * FROM scheduled_expenses WHERE budgetid = $1 ORDER BY date, id",
+            id
+        )
+            .fetch_all(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&scheduled), StatusCode::OK))
+    }
+
+    async fn handle_get_scheduled(id: i32, claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let scheduled = sqlx::query_as!(ScheduledExpense, "SELECT * FROM scheduled_expenses WHERE id = $1", id)
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        if !user_owns_budget(claims.user_id, scheduled.budgetid, &pool, ServiceError::Unauthorized).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        Ok(warp::reply::with_status(warp::reply::json(&scheduled), StatusCode::OK))
+    }
+
+    async fn handle_create_scheduled(claims: Claims, new_scheduled: NewScheduledExpense, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        Self::validate(&new_scheduled)?;
+
+        match budget_lock_state(claims.user_id, new_scheduled.budgetid, &pool).await? {
+            None => return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            )),
+            Some(true) => return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Budget is locked"})),
+                StatusCode::LOCKED,
+            )),
+            Some(false) => {},
+        }
+
+        let scheduled = sqlx::query_as!(
+            ScheduledExpense,
+            "INSERT INTO scheduled_expenses (budgetid, date, description, amount, created_by) VALUES ($1, $2, $3, $4, $5) RETURNING id, budgetid, date, description, amount, created_by",
+            new_scheduled.budgetid,
+            new_scheduled.date,
+            new_scheduled.description,
+            new_scheduled.amount,
+            claims.user_id
+        )
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&scheduled), StatusCode::CREATED))
+    }
+
+    async fn handle_update_scheduled(id: i32, claims: Claims, new_scheduled: NewScheduledExpense, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        Self::validate(&new_scheduled)?;
+
+        let existing = sqlx::query_as!(ScheduledExpense, "SELECT * FROM scheduled_expenses WHERE id = $1", id)
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        // Both the budget the scheduled expense is in and the one it moves to must be writable.
+        for budgetid in [existing.budgetid, new_scheduled.budgetid] {
+            match budget_lock_state(claims.user_id, budgetid, &pool).await? {
+                None => return Ok(warp::reply::with_status(
+                    warp::reply::json(&json!({"error": "Unauthorized"})),
+                    StatusCode::UNAUTHORIZED,
+                )),
+                Some(true) => return Ok(warp::reply::with_status(
+                    warp::reply::json(&json!({"error": "Budget is locked"})),
+                    StatusCode::LOCKED,
+                )),
+                Some(false) => {},
+            }
+        }
+
+        let scheduled = sqlx::query_as!(
+            ScheduledExpense,
+            "UPDATE scheduled_expenses SET budgetid = $1, date = $2, description = $3, amount = $4 WHERE id = $5 RETURNING id, budgetid, date, description, amount, created_by",
+            new_scheduled.budgetid,
+            new_scheduled.date,
+            new_scheduled.description,
+            new_scheduled.amount,
+            id
+        )
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&scheduled), StatusCode::OK))
+    }
+
+    async fn handle_delete_scheduled(id: i32, claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let scheduled = sqlx::query_as!(ScheduledExpense, "SELECT * FROM scheduled_expenses WHERE id = $1", id)
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        match budget_lock_state(claims.user_id, scheduled.budgetid, &pool).await? {
+            None => return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            )),
+            Some(true) => return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Budget is locked"})),
+                StatusCode::LOCKED,
+            )),
+            Some(false) => {},
+        }
+
+        sqlx::query!("DELETE FROM scheduled_expenses WHERE id = $1", id)
+            .execute(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&format!("Scheduled expense with id {} deleted", id)), StatusCode::OK))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use crate::utils::handle_rejection;
+
+    async fn lock(pool: &sqlx::PgPool, budget: i32) {
+        sqlx::query("UPDATE budgets SET locked = TRUE WHERE id = $1").bind(budget).execute(pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn create_scheduled_rejects_locked_budget(pool: sqlx::PgPool) {
+        let user = test_support::user(&pool, "owner").await;
+        let budget = test_support::budget(&pool, user).await;
+        lock(&pool, budget).await;
+        let routes = ScheduledExpenseService::new(pool.clone()).routes().recover(handle_rejection);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/scheduled_expenses")
+            .header("authorization", test_support::bearer(user))
+            .json(&json!({"budgetid": budget, "date": OffsetDateTime::now_utc().date(), "description": "Rent", "amount": "900"}))
+            .reply(&routes)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::LOCKED);
+    }
+
+    #[sqlx::test]
+    async fn materialize_due_skips_locked_budgets_and_rounds(pool: sqlx::PgPool) {
+        let user = test_support::user(&pool, "owner").await;
+        let open = test_support::budget(&pool, user).await;
+        let locked = test_support::budget(&pool, user).await;
+        lock(&pool, locked).await;
+        for budget in [open, locked] {
+            sqlx::query("INSERT INTO scheduled_expenses (budgetid, date, description, amount) VALUES ($1, CURRENT_DATE, 'Rent', 10.005)")
+                .bind(budget)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(ScheduledExpenseService::materialize_due(&pool).await.unwrap(), 1);
+
+        let amount: String = sqlx::query_scalar("SELECT amount::TEXT FROM expenses WHERE budgetid = $1").bind(open).fetch_one(&pool).await.unwrap();
+        assert_eq!(amount, "10.01");
+        let waiting: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM scheduled_expenses WHERE budgetid = $1").bind(locked).fetch_one(&pool).await.unwrap();
+        assert_eq!(waiting, 1);
+    }
+}
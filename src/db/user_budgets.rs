@@ -1,8 +1,8 @@
 use warp::{Filter, http::StatusCode};
-use sqlx::postgres::PgPoolOptions;
-use crate::utils::{json_body, with_db, user_owns_budget, ServiceError};
+use crate::utils::{json_body, with_db, user_owns_budget, user_is_budget_owner, begin, db_rejection, ServiceError};
 use serde::{Deserialize, Serialize};
 use crate::auth::{with_auth, Claims};
+use crate::logging;
 use serde_json::json;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -11,18 +11,19 @@ struct UserBudgetAssociation {
     budgetid: i32,
 }
 
+#[derive(Serialize, Debug)]
+struct BudgetUser {
+    id: i32,
+    name: String,
+    role: String,
+}
+
 pub struct UserBudgetService {
     pool: sqlx::PgPool,
 }
 
 impl UserBudgetService {
-    pub async fn new(database_url: &str) -> Self {
-        let pool = PgPoolOptions::new()
-            .max_connections(5)
-            .connect(database_url)
-            .await
-            .expect("Failed to create pool");
-
+    pub fn new(pool: sqlx::PgPool) -> Self {
         UserBudgetService { pool }
     }
 
@@ -31,7 +32,7 @@ impl UserBudgetService {
 
         let add_association = warp::path("user_budgets")
             .and(warp::post())
-            .and(with_auth())
+            .and(with_auth(pool.clone()))
             .and(json_body())
             .and(with_db(pool.clone()))
             .and_then(Self::handle_add_association)
@@ -39,20 +40,59 @@ impl UserBudgetService {
 
         let remove_association = warp::path("user_budgets")
             .and(warp::delete())
-            .and(with_auth())
+            .and(with_auth(pool.clone()))
             .and(warp::query::<UserBudgetAssociation>())
             .and(with_db(pool.clone()))
             .and_then(Self::handle_remove_association)
             .with(warp::log("api::remove_association"));
 
-        add_association.or(remove_association)
+        let get_budget_users = warp::path!("budgets" / i32 / "users")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_budget_users)
+            .with(warp::log("api::get_budget_users"));
+
+        add_association.or(remove_association).or(get_budget_users)
     }
 
+    /// Everyone the budget is shared with, so an owner can review collaborators before removing one.
+    async fn handle_get_budget_users(budget_id: i32, claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        if !user_owns_budget(claims.user_id, budget_id, &pool, ServiceError::Unauthorized).await? {
+            log::warn!(
+                "Unauthorized access attempt by user {} for budget {}",
+                logging::id(claims.user_id), logging::id(budget_id)
+            );
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let users = sqlx::query_as!(
+            BudgetUser,
+            "SELECT u.id, u.name, ub.role
+             FROM users u
+             JOIN user_budgets ub ON u.id = ub.userid
+             WHERE ub.budgetid = $1
+             ORDER BY u.name, u.id",
+            budget_id
+        )
+            .fetch_all(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&users), StatusCode::OK))
+    }
+
+    /// Only an owner can share a budget; the new collaborator joins as a member.
     async fn handle_add_association(claims: Claims, association: UserBudgetAssociation, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
-        if !user_owns_budget(claims.user_id, association.budgetid, &pool, ServiceError::Unauthorized).await? {
+        let mut tx = begin(&pool).await?;
+
+        if !user_is_budget_owner(claims.user_id, association.budgetid, &mut *tx, ServiceError::Unauthorized).await? {
             log::warn!(
                 "Unauthorized access attempt by user {} for budget {}",
-                claims.user_id, association.budgetid
+                logging::id(claims.user_id), logging::id(association.budgetid)
             );
             return Ok(warp::reply::with_status(
                 warp::reply::json(&json!({"error": "Unauthorized"})),
@@ -61,16 +101,19 @@ impl UserBudgetService {
         }
 
         match sqlx::query!(
-            "INSERT INTO user_budgets (userid, budgetid) VALUES ($1, $2)",
+            "INSERT INTO user_budgets (userid, budgetid, role) VALUES ($1, $2, 'member')",
             association.userid,
             association.budgetid
         )
-            .execute(&pool)
+            .execute(&mut *tx)
             .await {
             Ok(_) => {
+                tx.commit()
+                    .await
+                    .map_err(db_rejection)?;
                 log::info!(
                     "Successfully associated user {} with budget {}",
-                    association.userid, association.budgetid
+                    logging::id(association.userid), logging::id(association.budgetid)
                 );
                 Ok(warp::reply::with_status(
                     warp::reply::json(&format!(
@@ -81,17 +124,23 @@ impl UserBudgetService {
                 ))
             },
             Err(e) => {
-                log::error!("Failed to insert association for user {} and budget {}: {:?}", association.userid, association.budgetid, e);
-                Err(warp::reject::custom(ServiceError::DatabaseError(e)))
+                log::error!(
+                    "Failed to insert association for user {} and budget {}: {}",
+                    logging::id(association.userid), logging::id(association.budgetid), logging::db_error(&e)
+                );
+                Err(db_rejection(e))
             },
         }
     }
 
+    /// Only an owner can remove collaborators, and the budget's last owner can't be removed.
     async fn handle_remove_association(claims: Claims, query: UserBudgetAssociation, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
-        if !user_owns_budget(claims.user_id, query.budgetid, &pool, ServiceError::Unauthorized).await? {
+        let mut tx = begin(&pool).await?;
+
+        if !user_is_budget_owner(claims.user_id, query.budgetid, &mut *tx, ServiceError::Unauthorized).await? {
             log::warn!(
                 "Unauthorized access attempt by user {} for budget {}",
-                claims.user_id, query.budgetid
+                logging::id(claims.user_id), logging::id(query.budgetid)
             );
             return Ok(warp::reply::with_status(
                 warp::reply::json(&json!({"error": "Unauthorized"})),
@@ -104,12 +153,28 @@ impl UserBudgetService {
             query.userid,
             query.budgetid
         )
-            .execute(&pool)
+            .execute(&mut *tx)
             .await {
             Ok(_) => {
+                let owners = sqlx::query_scalar!(
+                    r#"SELECT COUNT(*) AS "count!" FROM user_budgets WHERE budgetid = $1 AND role = 'owner'"#,
+                    query.budgetid
+                )
+                    .fetch_one(&mut *tx)
+                    .await
+                    .map_err(db_rejection)?;
+                if owners == 0 {
+                    return Err(warp::reject::custom(ServiceError::Conflict(
+                        "A budget must keep an owner; transfer ownership before removing the last one".into(),
+                    )));
+                }
+
+                tx.commit()
+                    .await
+                    .map_err(db_rejection)?;
                 log::info!(
                     "Successfully removed association of user {} with budget {}",
-                    query.userid, query.budgetid
+                    logging::id(query.userid), logging::id(query.budgetid)
                 );
                 Ok(warp::reply::with_status(
                     warp::reply::json(&format!(
@@ -120,8 +185,11 @@ impl UserBudgetService {
                 ))
             },
             Err(e) => {
-                log::error!("Failed to delete association for user {} and budget {}: {:?}", query.userid, query.budgetid, e);
-                Err(warp::reject::custom(ServiceError::DatabaseError(e)))
+                log::error!(
+                    "Failed to delete association for user {} and budget {}: {}",
+                    logging::id(query.userid), logging::id(query.budgetid), logging::db_error(&e)
+                );
+                Err(db_rejection(e))
             },
         }
     }
@@ -1,32 +1,36 @@
-use warp::{Filter};
+use warp::{Filter, Reply};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use sqlx::postgres::PgPoolOptions;
-use crate::utils::{json_body, with_db, ServiceError};
+use crate::utils::{json_body, with_db, begin, db_rejection, ServiceError};
 use bcrypt::{hash, verify};
 use jsonwebtoken::{encode, Header, EncodingKey};
 use warp::http::StatusCode;
-use std::convert::Infallible;
 use std::env;
-use crate::auth::{with_auth, Claims};
-
-#[derive(Serialize, Deserialize, Debug)]
-struct User {
-    id: i32,
-    name: String,
-    password: Option<String>,
-}
+use time::{Date, OffsetDateTime};
+use bigdecimal::BigDecimal;
+use uuid::Uuid;
+use crate::auth::{with_auth, jwt_secret, Claims};
+use crate::rate_limit::{with_limiter, LoginLimiter};
 
 #[derive(Deserialize, Debug)]
 struct NewUser {
     name: String,
     password: String,
+    email: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct UserResponse {
     id: i32,
     name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChangePassword {
+    old_password: String,
+    new_password: String,
 }
 
 #[derive(Deserialize, Debug)]
@@ -35,88 +39,333 @@ struct LoginRequest {
     password: String,
 }
 
+/// Names the account to reset by either its username or its email address.
+#[derive(Deserialize, Debug)]
+struct PasswordResetRequest {
+    name: Option<String>,
+    email: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PasswordResetConfirm {
+    token: Uuid,
+    new_password: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct LoginResponse {
     id: i32,
     name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
     token: String,
 }
 
+/// Everything stored about a user, for `GET /users/{id}/export`.
+#[derive(Serialize, Debug)]
+struct UserExport {
+    user: UserResponse,
+    budgets: Vec<ExportedBudget>,
+    expenses: Vec<ExportedExpense>,
+}
+
+#[derive(Serialize, Debug)]
+struct ExportedBudget {
+    id: i32,
+    name: String,
+    settings: serde_json::Value,
+    locked: bool,
+    archived: bool,
+    role: String,
+}
+
+/// `created_by` is only kept for the user's own entries, so the export names no other member.
+#[derive(Serialize, Debug)]
+struct ExportedExpense {
+    id: i32,
+    budgetid: i32,
+    date: Date,
+    description: String,
+    amount: BigDecimal,
+    draft: bool,
+    custom_data: Option<serde_json::Value>,
+    reimbursed: bool,
+    reimbursed_at: Option<Date>,
+    category: Option<String>,
+    entry_type: String,
+    receipt_url: Option<String>,
+    created_by: Option<i32>,
+}
+
+const DEFAULT_BCRYPT_COST: u32 = 12;
+const DEFAULT_TOKEN_TTL_SECS: u64 = 90 * 24 * 60 * 60;
+const DEFAULT_PASSWORD_MIN_LENGTH: usize = 8;
+/// How long a password reset token can be used for.
+const PASSWORD_RESET_TTL_MINUTES: i32 = 60;
+
+/// What a new password must satisfy: at least `PASSWORD_MIN_LENGTH` characters (default 8) and,
+/// with `PASSWORD_REQUIRE_MIXED=true`, both letters and digits.
+#[derive(Debug, Clone, Copy)]
+struct PasswordPolicy {
+    min_length: usize,
+    require_mixed: bool,
+}
+
+impl PasswordPolicy {
+    fn from_env() -> Self {
+        let min_length = match env::var("PASSWORD_MIN_LENGTH") {
+            Ok(value) => match value.parse::<usize>() {
+                Ok(length) if length > 0 => length,
+                _ => {
+                    log::warn!("PASSWORD_MIN_LENGTH must be a positive number, using default of {}", DEFAULT_PASSWORD_MIN_LENGTH);
+                    DEFAULT_PASSWORD_MIN_LENGTH
+                },
+            },
+            Err(_) => DEFAULT_PASSWORD_MIN_LENGTH,
+        };
+        let require_mixed = env::var("PASSWORD_REQUIRE_MIXED").is_ok_and(|value| value == "true");
+
+        PasswordPolicy { min_length, require_mixed }
+    }
+
+    fn check(&self, password: &str) -> Result<(), warp::Rejection> {
+        let reject = |detail: String| Err(warp::reject::custom(ServiceError::BadRequest(detail)));
+
+        if password.chars().count() < self.min_length {
+            return reject(format!("password must be at least {} characters", self.min_length));
+        }
+
+        if self.require_mixed
+            && !(password.chars().any(char::is_alphabetic) && password.chars().any(|c| c.is_ascii_digit())) {
+            return reject("password must contain both letters and digits".into());
+        }
+
+        Ok(())
+    }
+}
+
 pub struct UserService {
     pool: sqlx::PgPool,
+    bcrypt_cost: u32,
+    token_ttl: u64,
+    password_policy: PasswordPolicy,
+    login_limiter: LoginLimiter,
 }
 
 impl UserService {
-    pub async fn new(database_url: &str) -> Self {
-        let pool = PgPoolOptions::new()
-            .max_connections(5)
-            .connect(database_url)
+    pub fn new(pool: sqlx::PgPool, login_limiter: LoginLimiter) -> Self {
+        UserService {
+            pool,
+            bcrypt_cost: Self::bcrypt_cost(),
+            token_ttl: Self::token_ttl(),
+            password_policy: PasswordPolicy::from_env(),
+            login_limiter,
+        }
+    }
+
+    /// Reads `JWT_TTL_SECONDS`, how long issued tokens stay valid (default 90 days). A value that
+    /// isn't a positive number of seconds stops startup rather than issuing unintended tokens.
+    fn token_ttl() -> u64 {
+        match env::var("JWT_TTL_SECONDS") {
+            Ok(value) => match value.parse::<u64>() {
+                Ok(secs) if secs > 0 => secs,
+                _ => panic!("JWT_TTL_SECONDS must be a positive number of seconds, got {}", value),
+            },
+            Err(_) => DEFAULT_TOKEN_TTL_SECS,
+        }
+    }
+
+    /// Reads `BCRYPT_COST`, falling back to the default when it is unset or outside bcrypt's 4..=31.
+    fn bcrypt_cost() -> u32 {
+        match env::var("BCRYPT_COST") {
+            Ok(value) => match value.parse::<u32>() {
+                Ok(cost) if (4..=31).contains(&cost) => cost,
+                _ => {
+                    log::warn!("BCRYPT_COST must be between 4 and 31, using default of {}", DEFAULT_BCRYPT_COST);
+                    DEFAULT_BCRYPT_COST
+                },
+            },
+            Err(_) => DEFAULT_BCRYPT_COST,
+        }
+    }
+
+    /// Hashes on the blocking pool, since higher costs take long enough to stall other requests.
+    async fn hash_password(password: String, cost: u32) -> Result<String, warp::Rejection> {
+        tokio::task::spawn_blocking(move || hash(password, cost))
             .await
-            .expect("Failed to create pool");
+            .map_err(|_| warp::reject::custom(ServiceError::InternalServerError))?
+            .map_err(|_| warp::reject::custom(ServiceError::BadRequest("Hashing error".into())))
+    }
 
-        UserService { pool }
+    /// Verifies on the blocking pool for the same reason as `hash_password`.
+    async fn verify_password(password: String, hashed_password: String) -> Result<bool, warp::Rejection> {
+        tokio::task::spawn_blocking(move || verify(password, &hashed_password).unwrap_or(false))
+            .await
+            .map_err(|_| warp::reject::custom(ServiceError::InternalServerError))
     }
 
     pub fn routes(&self) -> impl Filter<Extract=impl warp::Reply, Error=warp::Rejection> + Clone {
         let pool = self.pool.clone();
+        let bcrypt_cost = self.bcrypt_cost;
+        let with_cost = warp::any().map(move || bcrypt_cost);
+        let token_ttl = self.token_ttl;
+        let with_ttl = warp::any().map(move || token_ttl);
+        let password_policy = self.password_policy;
+        let with_policy = warp::any().map(move || password_policy);
 
         let create_user = warp::path("users")
             .and(warp::post())
             .and(json_body())
             .and(with_db(pool.clone()))
+            .and(with_cost)
+            .and(with_policy)
+            .and(with_ttl)
             .and_then(Self::handle_create_user);
 
         let update_user = warp::path!("users" / i32)
             .and(warp::put())
-            .and(with_auth())
+            .and(with_auth(pool.clone()))
             .and(json_body())
             .and(with_db(pool.clone()))
+            .and(with_cost)
+            .and(with_policy)
             .and_then(Self::handle_update_user);
 
+        let change_password = warp::path!("users" / i32 / "password")
+            .and(warp::post())
+            .and(with_auth(pool.clone()))
+            .and(json_body())
+            .and(with_db(pool.clone()))
+            .and(with_cost)
+            .and(with_policy)
+            .and_then(Self::handle_change_password);
+
+        let export_user = warp::path!("users" / i32 / "export")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_export_user);
+
         let delete_user = warp::path!("users" / i32)
             .and(warp::delete())
-            .and(with_auth())
+            .and(with_auth(pool.clone()))
             .and(with_db(pool.clone()))
             .and_then(Self::handle_delete_user);
 
         let login = warp::path("login")
             .and(warp::post())
             .and(json_body())
-            .and(with_db(pool))
+            .and(with_db(pool.clone()))
+            .and(with_limiter(self.login_limiter.clone()))
+            .and(with_ttl)
             .and_then(Self::handle_login);
 
+        let get_me = warp::path!("me")
+            .and(warp::get())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_get_me);
+
+        let refresh = warp::path("refresh")
+            .and(warp::post())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and(with_ttl)
+            .and_then(Self::handle_refresh);
+
+        let logout = warp::path("logout")
+            .and(warp::post())
+            .and(with_auth(pool.clone()))
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_logout);
+
+        let request_password_reset = warp::path!("password-reset" / "request")
+            .and(warp::post())
+            .and(json_body())
+            .and(with_db(pool.clone()))
+            .and_then(Self::handle_request_password_reset);
+
+        let confirm_password_reset = warp::path!("password-reset" / "confirm")
+            .and(warp::post())
+            .and(json_body())
+            .and(with_db(pool))
+            .and(with_cost)
+            .and(with_policy)
+            .and_then(Self::handle_confirm_password_reset);
+
         create_user
             .or(update_user)
+            .or(change_password)
+            .or(export_user)
             .or(delete_user)
             .or(login)
+            .or(get_me)
+            .or(refresh)
+            .or(logout)
+            .or(request_password_reset)
+            .or(confirm_password_reset)
+    }
+
+    /// Usernames are stored without surrounding whitespace and matched ignoring case.
+    fn normalize_name(name: &str) -> Result<&str, warp::Rejection> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(warp::reject::custom(ServiceError::BadRequest("name must not be empty".into())));
+        }
+        Ok(name)
+    }
+
+    /// Trims an optional email address and checks it has the shape `local@domain.tld`, without
+    /// whitespace and at most 254 characters long.
+    fn normalize_email(email: Option<&str>) -> Result<Option<&str>, warp::Rejection> {
+        let email = match email {
+            Some(email) => email.trim(),
+            None => return Ok(None),
+        };
+
+        let valid = email.len() <= 254
+            && !email.chars().any(char::is_whitespace)
+            && email.split_once('@').is_some_and(|(local, domain)| {
+                !local.is_empty()
+                    && !domain.contains('@')
+                    && domain.split('.').count() > 1
+                    && domain.split('.').all(|label| !label.is_empty())
+            });
+        if !valid {
+            return Err(warp::reject::custom(ServiceError::BadRequest("email must be a valid email address".into())));
+        }
+        Ok(Some(email))
     }
 
-    async fn handle_create_user(new_user: NewUser, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
-        let hashed_password = hash(&new_user.password, 4).map_err(|_| {
-            warp::reject::custom(ServiceError::BadRequest("Hashing error".into()))
-        })?;
+    async fn handle_create_user(new_user: NewUser, pool: sqlx::PgPool, bcrypt_cost: u32, password_policy: PasswordPolicy, token_ttl: u64) -> Result<impl warp::Reply, warp::Rejection> {
+        let name = Self::normalize_name(&new_user.name)?;
+        let email = Self::normalize_email(new_user.email.as_deref())?;
+        password_policy.check(&new_user.password)?;
+        let hashed_password = Self::hash_password(new_user.password, bcrypt_cost).await?;
 
         let user = sqlx::query!(
-            "INSERT INTO users (name, password) VALUES ($1, $2) RETURNING id, name",
-            new_user.name,
-            hashed_password
+            "INSERT INTO users (name, password, email) VALUES ($1, $2, $3) RETURNING id, name, email",
+            name,
+            hashed_password,
+            email
         )
             .fetch_one(&pool)
             .await
-            .map_err(|err| warp::reject::custom(ServiceError::DatabaseError(err)))?;
+            .map_err(Self::name_taken)?;
 
-        let token = Self::generate_token(user.id)?;
+        let token = Self::generate_token(user.id, &user.name, token_ttl)?;
 
         let login_response = LoginResponse {
             id: user.id,
             name: user.name,
+            email: user.email,
             token,
         };
 
         Ok(warp::reply::with_status(warp::reply::json(&login_response), StatusCode::CREATED))
     }
 
-    async fn handle_update_user(id: i32, claims: Claims, new_user: NewUser, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+    async fn handle_update_user(id: i32, claims: Claims, new_user: NewUser, pool: sqlx::PgPool, bcrypt_cost: u32, password_policy: PasswordPolicy) -> Result<impl warp::Reply, warp::Rejection> {
         if id != claims.user_id {
             return Ok(warp::reply::with_status(
                 warp::reply::json(&json!({"error": "Unauthorized"})),
@@ -124,29 +373,32 @@ impl UserService {
             ));
         }
 
-        let hashed_password = hash(&new_user.password, 4).map_err(|_| {
-            warp::reject::custom(ServiceError::BadRequest("Hashing error".into()))
-        })?;
+        let name = Self::normalize_name(&new_user.name)?;
+        let email = Self::normalize_email(new_user.email.as_deref())?;
+        password_policy.check(&new_user.password)?;
+        let hashed_password = Self::hash_password(new_user.password, bcrypt_cost).await?;
 
         let user = sqlx::query!(
-            "UPDATE users SET name = $1, password = $2 WHERE id = $3 RETURNING id, name",
-            new_user.name,
+            "UPDATE users SET name = $1, password = $2, email = $3 WHERE id = $4 RETURNING id, name, email",
+            name,
             hashed_password,
+            email,
             id
         )
             .fetch_one(&pool)
             .await
-            .map_err(|err| warp::reject::custom(ServiceError::DatabaseError(err)))?;
+            .map_err(Self::name_taken)?;
 
         let user_response = UserResponse {
             id: user.id,
             name: user.name,
+            email: user.email,
         };
 
         Ok(warp::reply::with_status(warp::reply::json(&user_response), StatusCode::OK))
     }
 
-    async fn handle_delete_user(id: i32, claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+    async fn handle_change_password(id: i32, claims: Claims, change: ChangePassword, pool: sqlx::PgPool, bcrypt_cost: u32, password_policy: PasswordPolicy) -> Result<impl warp::Reply, warp::Rejection> {
         if id != claims.user_id {
             return Ok(warp::reply::with_status(
                 warp::reply::json(&json!({"error": "Unauthorized"})),
@@ -154,118 +406,417 @@ impl UserService {
             ));
         }
 
-        // Step 1: Fetch budget IDs associated with user from user_budgets table
-        let budgetids: Vec<i32> = sqlx::query!("SELECT budgetid FROM user_budgets WHERE userid = $1", id)
-            .fetch_all(&pool)
+        password_policy.check(&change.new_password)?;
+
+        let record = sqlx::query!("SELECT password FROM users WHERE id = $1", id)
+            .fetch_one(&pool)
             .await
-            .map_err(|err| warp::reject::custom(ServiceError::DatabaseError(err)))?
-            .into_iter()
-            .map(|record| record.budgetid)
-            .collect();
+            .map_err(db_rejection)?;
 
-        // Step 2: Delete budget expenses from expenses table
-        for budgetid in budgetids.iter() {
-            sqlx::query!("DELETE FROM expenses WHERE budgetid = $1", budgetid)
-                .execute(&pool)
-                .await
-                .map_err(|err| warp::reject::custom(ServiceError::DatabaseError(err)))?;
+        if !Self::verify_password(change.old_password, record.password).await? {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Invalid credentials"})),
+                StatusCode::UNAUTHORIZED,
+            ));
         }
 
-        // Step 3: Delete budget from budgets table
-        for budgetid in budgetids.iter() {
-            sqlx::query!("DELETE FROM budgets WHERE id = $1", budgetid)
+        let hashed_password = Self::hash_password(change.new_password, bcrypt_cost).await?;
+
+        sqlx::query!("UPDATE users SET password = $1 WHERE id = $2", hashed_password, id)
+            .execute(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&format!("Password for user {} changed", id)), StatusCode::OK))
+    }
+
+    /// Issues a single-use token that resets the password of the account with the given name or
+    /// email, valid for `PASSWORD_RESET_TTL_MINUTES`. Until reset emails are sent, the token is
+    /// only written to the log. The reply is the same whether or not the account exists, so the
+    /// endpoint can't be used to discover accounts.
+    async fn handle_request_password_reset(request: PasswordResetRequest, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let user_id = match (request.name.as_deref(), request.email.as_deref()) {
+            (Some(name), None) => {
+                sqlx::query_scalar!("SELECT id FROM users WHERE LOWER(name) = LOWER($1)", name.trim())
+                    .fetch_optional(&pool)
+                    .await
+                    .map_err(db_rejection)?
+            },
+            (None, Some(email)) => {
+                sqlx::query_scalar!("SELECT id FROM users WHERE LOWER(email) = LOWER($1)", email.trim())
+                    .fetch_optional(&pool)
+                    .await
+                    .map_err(db_rejection)?
+            },
+            _ => return Err(warp::reject::custom(ServiceError::BadRequest("exactly one of name or email is required".into()))),
+        };
+
+        if let Some(user_id) = user_id {
+            let token = Uuid::new_v4();
+            sqlx::query!(
+                "INSERT INTO password_resets (token, user_id, expires_at) VALUES ($1, $2, now() + make_interval(mins => $3))",
+                token,
+                user_id,
+                PASSWORD_RESET_TTL_MINUTES
+            )
                 .execute(&pool)
                 .await
-                .map_err(|err| warp::reject::custom(ServiceError::DatabaseError(err)))?;
+                .map_err(db_rejection)?;
+
+            log::info!("Password reset token for user {}: {}", crate::logging::id(user_id), token);
         }
 
-        // Step 4: Delete user/budget associations from user_budgets table
-        sqlx::query!("DELETE FROM user_budgets WHERE userid = $1", id)
-            .execute(&pool)
+        Ok(warp::reply::with_status(
+            warp::reply::json(&"If the account exists, a password reset has been issued"),
+            StatusCode::ACCEPTED,
+        ))
+    }
+
+    /// Sets a new password with an unexpired, unused reset token. Using a token also drops any
+    /// other outstanding tokens for the account.
+    async fn handle_confirm_password_reset(confirm: PasswordResetConfirm, pool: sqlx::PgPool, bcrypt_cost: u32, password_policy: PasswordPolicy) -> Result<impl warp::Reply, warp::Rejection> {
+        password_policy.check(&confirm.new_password)?;
+        let hashed_password = Self::hash_password(confirm.new_password, bcrypt_cost).await?;
+
+        let mut tx = begin(&pool).await?;
+
+        let user_id = sqlx::query_scalar!(
+            "UPDATE password_resets SET used_at = now() WHERE token = $1 AND used_at IS NULL AND expires_at > now() RETURNING user_id",
+            confirm.token
+        )
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(db_rejection)?
+            .ok_or_else(|| warp::reject::custom(ServiceError::BadRequest("reset token is invalid or has expired".into())))?;
+
+        sqlx::query!("UPDATE users SET password = $1 WHERE id = $2", hashed_password, user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(db_rejection)?;
+
+        sqlx::query!("DELETE FROM password_resets WHERE user_id = $1 AND used_at IS NULL", user_id)
+            .execute(&mut *tx)
             .await
-            .map_err(|err| warp::reject::custom(ServiceError::DatabaseError(err)))?;
+            .map_err(db_rejection)?;
 
-        // Step 5: Delete user from users table
+        tx.commit()
+            .await
+            .map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&"Password has been reset"), StatusCode::OK))
+    }
+
+    /// The caller's profile, every budget they belong to and all expenses in those budgets, as
+    /// a JSON attachment. Other members of shared budgets are left out.
+    async fn handle_export_user(id: i32, claims: Claims, pool: sqlx::PgPool) -> Result<warp::reply::Response, warp::Rejection> {
+        if id != claims.user_id {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ).into_response());
+        }
+
+        let user = sqlx::query_as!(UserResponse, "SELECT id, name, email FROM users WHERE id = $1", id)
+            .fetch_one(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        let budgets = sqlx::query_as!(
+            ExportedBudget,
+            "SELECT b.id, b.name, b.settings, b.locked, b.archived, ub.role
+             FROM budgets b
+             JOIN user_budgets ub ON ub.budgetid = b.id
+             WHERE ub.userid = $1
+             ORDER BY b.id",
+            id
+        )
+            .fetch_all(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        let expenses = sqlx::query_as!(
+            ExportedExpense,
+            r#"SELECT e.id, e.budgetid, e.date, e.description, e.amount, e.draft, e.custom_data, e.reimbursed, e.reimbursed_at, e.category, e.entry_type, e.receipt_url,
+                      CASE WHEN e.created_by = $1 THEN e.created_by END AS created_by
+               FROM expenses e
+               JOIN user_budgets ub ON ub.budgetid = e.budgetid
+               WHERE ub.userid = $1
+               ORDER BY e.budgetid, e.date, e.id"#,
+            id
+        )
+            .fetch_all(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        let export = UserExport { user, budgets, expenses };
+        let reply = warp::reply::with_header(
+            warp::reply::json(&export),
+            "Content-Disposition",
+            format!("attachment; filename=\"ardpie-export-{}.json\"", id),
+        );
+        Ok(reply.into_response())
+    }
+
+    async fn handle_delete_user(id: i32, claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        if id != claims.user_id {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let mut tx = pool.begin().await.map_err(db_rejection)?;
+
+        // Step 1: Fetch the budgets only this user belongs to; shared budgets stay with the other members
+        let budgetids: Vec<i32> = sqlx::query!(
+            "SELECT ub.budgetid FROM user_budgets ub
+             WHERE ub.userid = $1
+               AND NOT EXISTS (SELECT 1 FROM user_budgets other WHERE other.budgetid = ub.budgetid AND other.userid <> $1)",
+            id
+        )
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(db_rejection)?
+            .into_iter()
+            .map(|record| record.budgetid)
+            .collect();
+
+        // Step 2: Delete those budgets' expenses and scheduled expenses
+        sqlx::query!("DELETE FROM expenses WHERE budgetid = ANY($1)", &budgetids)
+            .execute(&mut *tx)
+            .await
+            .map_err(db_rejection)?;
+
+        sqlx::query!("DELETE FROM scheduled_expenses WHERE budgetid = ANY($1)", &budgetids)
+            .execute(&mut *tx)
+            .await
+            .map_err(db_rejection)?;
+
+        // Step 3: Delete all of the user's associations from user_budgets table
+        let shared_budgetids: Vec<i32> = sqlx::query!("DELETE FROM user_budgets WHERE userid = $1 RETURNING budgetid", id)
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(db_rejection)?
+            .into_iter()
+            .map(|record| record.budgetid)
+            .filter(|budgetid| !budgetids.contains(budgetid))
+            .collect();
+
+        // Step 4: Shared budgets left without an owner pass to their longest-standing remaining member
+        sqlx::query!(
+            "UPDATE user_budgets ub SET role = 'owner'
+             WHERE ub.budgetid = ANY($1)
+               AND ub.userid = (SELECT MIN(m.userid) FROM user_budgets m WHERE m.budgetid = ub.budgetid)
+               AND NOT EXISTS (SELECT 1 FROM user_budgets o WHERE o.budgetid = ub.budgetid AND o.role = 'owner')",
+            &shared_budgetids
+        )
+            .execute(&mut *tx)
+            .await
+            .map_err(db_rejection)?;
+
+        // Step 5: Delete the now-unreferenced budgets from budgets table
+        sqlx::query!("DELETE FROM budgets WHERE id = ANY($1)", &budgetids)
+            .execute(&mut *tx)
+            .await
+            .map_err(db_rejection)?;
+
+        // Step 6: Delete user from users table
         sqlx::query!("DELETE FROM users WHERE id = $1", id)
-            .execute(&pool)
+            .execute(&mut *tx)
             .await
-            .map_err(|err| warp::reject::custom(ServiceError::DatabaseError(err)))?;
+            .map_err(db_rejection)?;
+
+        tx.commit().await.map_err(db_rejection)?;
 
         Ok(warp::reply::with_status(warp::reply::json(&format!("User with id {} deleted", id)), StatusCode::OK))
     }
 
-    async fn handle_login(login: LoginRequest, pool: sqlx::PgPool) -> Result<impl warp::Reply, Infallible> {
-        match sqlx::query!("SELECT id, name, password FROM users WHERE name = $1", login.name)
-            .fetch_one(&pool)
+    async fn handle_login(login: LoginRequest, pool: sqlx::PgPool, limiter: LoginLimiter, token_ttl: u64) -> Result<impl warp::Reply, warp::Rejection> {
+        // Throttle by the name as matched, so varying its case or padding doesn't reset the count.
+        let limiter_key = login.name.trim().to_lowercase();
+        if limiter.is_blocked(&limiter_key) {
+            log::warn!("Login throttled after repeated failures");
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Too many login attempts, try again later"})),
+                StatusCode::TOO_MANY_REQUESTS,
+            ));
+        }
+
+        let record = sqlx::query!("SELECT id, name, email, password FROM users WHERE LOWER(name) = LOWER($1)", login.name.trim())
+            .fetch_optional(&pool)
             .await
-        {
-            Ok(record) => {
-                let hashed_password = if !record.password.is_empty() {
-                    &record.password
-                } else {
-                    // Fallback to empty or error case if necessary
-                    ""
-                };
-
-                match verify(&login.password, &hashed_password) {
-                    Ok(is_valid) if is_valid => {
-                        match Self::generate_token(record.id) {
-                            Ok(token) => {
-                                let login_response = LoginResponse {
-                                    id: record.id,
-                                    name: record.name,
-                                    token,
-                                };
-
-                                Ok(warp::reply::with_status(
-                                    warp::reply::json(&login_response),
-                                    StatusCode::OK,
-                                ))
-                            }
-                            Err(err) => {
-                                let error_detail = format!("Database error: {:?}", err);
-                                Ok(warp::reply::with_status(
-                                    warp::reply::json(&json!({"error": "Internal server error", "details": error_detail})),
-                                    StatusCode::INTERNAL_SERVER_ERROR,
-                                ))
-                            }
-                        }
-                    },
-                    _ => Ok(warp::reply::with_status(
-                        warp::reply::json(&json!({"error": "Invalid credentials"})),
-                        StatusCode::UNAUTHORIZED,
-                    )),
-                }
-            }
-            Err(err) => {
-                let error_detail = format!("Database error: {}", err);
-                Ok(warp::reply::with_status(
-                    warp::reply::json(&json!({"error": "Invalid credentials", "details": error_detail})),
+            .map_err(db_rejection)?;
+
+        let record = match record {
+            Some(record) if Self::verify_password(login.password, record.password.clone()).await? => record,
+            _ => {
+                limiter.record_failure(&limiter_key);
+                return Ok(warp::reply::with_status(
+                    warp::reply::json(&json!({"error": "Invalid credentials"})),
                     StatusCode::UNAUTHORIZED,
-                ))
-            }
+                ));
+            },
+        };
+
+        limiter.reset(&limiter_key);
+        let token = Self::generate_token(record.id, &record.name, token_ttl)?;
+        let login_response = LoginResponse {
+            id: record.id,
+            name: record.name,
+            email: record.email,
+            token,
+        };
+
+        Ok(warp::reply::with_status(warp::reply::json(&login_response), StatusCode::OK))
+    }
+
+    async fn handle_get_me(claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let user = sqlx::query_as!(UserResponse, "SELECT id, name, email FROM users WHERE id = $1", claims.user_id)
+            .fetch_optional(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        match user {
+            Some(user) => Ok(warp::reply::with_status(warp::reply::json(&user), StatusCode::OK)),
+            None => Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "User not found"})),
+                StatusCode::NOT_FOUND,
+            )),
         }
     }
 
-    fn generate_token(user_id: i32) -> Result<String, warp::Rejection> {
+    /// Issues a fresh token for a caller whose token is still valid. Expired tokens are turned
+    /// away by `with_auth`, so a lapsed session still has to log in again.
+    async fn handle_refresh(claims: Claims, pool: sqlx::PgPool, token_ttl: u64) -> Result<impl warp::Reply, warp::Rejection> {
+        let user = sqlx::query!("SELECT id, name, email FROM users WHERE id = $1", claims.user_id)
+            .fetch_optional(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        let user = match user {
+            Some(user) => user,
+            None => return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": "Unauthorized"})),
+                StatusCode::UNAUTHORIZED,
+            )),
+        };
+
+        let token = Self::generate_token(user.id, &user.name, token_ttl)?;
+
+        let login_response = LoginResponse {
+            id: user.id,
+            name: user.name,
+            email: user.email,
+            token,
+        };
+
+        Ok(warp::reply::with_status(warp::reply::json(&login_response), StatusCode::OK))
+    }
+
+    /// Revokes the caller's token, so it is refused from now on even though it hasn't expired.
+    async fn handle_logout(claims: Claims, pool: sqlx::PgPool) -> Result<impl warp::Reply, warp::Rejection> {
+        let jti = claims.jti.ok_or_else(|| warp::reject::custom(ServiceError::BadRequest(
+            "This token predates logout and cannot be revoked; it stays valid until it expires".into(),
+        )))?;
+        let expires_at = OffsetDateTime::from_unix_timestamp(claims.exp as i64)
+            .map_err(|_| warp::reject::custom(ServiceError::InternalServerError))?;
+
+        sqlx::query!(
+            "INSERT INTO token_revocations (jti, expires_at) VALUES ($1, $2) ON CONFLICT (jti) DO NOTHING",
+            jti,
+            expires_at
+        )
+            .execute(&pool)
+            .await
+            .map_err(db_rejection)?;
+
+        Ok(warp::reply::with_status(warp::reply::json(&"Logged out"), StatusCode::OK))
+    }
+
+    /// Drops revocations of tokens that have since expired and are refused regardless.
+    pub async fn purge_token_revocations(pool: &sqlx::PgPool) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query!("DELETE FROM token_revocations WHERE expires_at < now()")
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Drops password reset tokens that have expired, used or not.
+    pub async fn purge_password_resets(pool: &sqlx::PgPool) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query!("DELETE FROM password_resets WHERE expires_at < now()")
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Reports a unique violation on `users.name` or `users.email` as a 409 rather than a database
+    /// failure.
+    fn name_taken(err: sqlx::Error) -> warp::Rejection {
+        match &err {
+            sqlx::Error::Database(db_err) if db_err.is_unique_violation() && db_err.constraint() == Some("users_email_key") => {
+                warp::reject::custom(ServiceError::Conflict("email already in use".into()))
+            },
+            sqlx::Error::Database(db_err) if db_err.is_unique_violation() => {
+                warp::reject::custom(ServiceError::Conflict("username already taken".into()))
+            },
+            _ => db_rejection(err),
+        }
+    }
+
+    fn generate_token(user_id: i32, name: &str, ttl: u64) -> Result<String, warp::Rejection> {
         let claims = Claims {
             user_id,
-            exp: Self::get_expires_at(),
+            name: name.to_string(),
+            exp: Self::get_expires_at(ttl),
+            jti: Some(Uuid::new_v4()),
         };
-        let secret = env::var("JWT_SECRET").map_err(|_| {
-            warp::reject::custom(ServiceError::InternalServerError)
-        })?;
-        let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_ref()))
+        let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(jwt_secret().as_ref()))
             .map_err(|_| {
                 warp::reject::custom(ServiceError::InternalServerError)
             })?;
         Ok(token)
     }
 
-    fn get_expires_at() -> usize {
+    fn get_expires_at(ttl: u64) -> usize {
         use std::time::{SystemTime, UNIX_EPOCH, Duration};
         let start = SystemTime::now();
         let since_the_epoch = start.duration_since(UNIX_EPOCH).expect("Time went backwards");
-        (since_the_epoch + Duration::from_secs(90 * 24 * 60 * 60)).as_secs() as usize
+        since_the_epoch.saturating_add(Duration::from_secs(ttl)).as_secs() as usize
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULT: PasswordPolicy = PasswordPolicy { min_length: DEFAULT_PASSWORD_MIN_LENGTH, require_mixed: false };
+
+    #[test]
+    fn password_policy_rejects_short_passwords() {
+        assert!(DEFAULT.check("seven77").is_err());
+        assert!(DEFAULT.check("").is_err());
+    }
+
+    #[test]
+    fn password_policy_counts_characters_not_bytes() {
+        assert!(DEFAULT.check("pässwörd").is_ok());
+        assert!(DEFAULT.check("ääääääa").is_err());
+    }
+
+    #[test]
+    fn password_policy_accepts_valid_passwords() {
+        assert!(DEFAULT.check("eight888").is_ok());
+        assert!(DEFAULT.check("correct horse battery staple").is_ok());
+    }
+
+    #[test]
+    fn password_policy_can_require_letters_and_digits() {
+        let mixed = PasswordPolicy { require_mixed: true, ..DEFAULT };
+
+        assert!(mixed.check("lettersonly").is_err());
+        assert!(mixed.check("1234567890").is_err());
+        assert!(mixed.check("letters123").is_ok());
+    }
+}
@@ -0,0 +1,36 @@
+use warp::Filter;
+use warp::http::header::CONTENT_TYPE;
+
+/// The OpenAPI 3.0 description of the API, kept by hand next to the routes it describes.
+const SPEC: &str = include_str!("openapi.json");
+
+/// Swagger UI, loaded from a CDN, pointed at the spec served alongside it.
+const SWAGGER_UI: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>ardpie API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>
+    window.ui = SwaggerUIBundle({ url: "openapi.json", dom_id: "#swagger-ui" });
+  </script>
+</body>
+</html>
+"##;
+
+/// Unauthenticated `GET /openapi.json` and `GET /docs`.
+pub fn routes() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let spec = warp::path!("openapi.json")
+        .and(warp::get())
+        .map(|| warp::reply::with_header(SPEC, CONTENT_TYPE, "application/json"));
+
+    let docs = warp::path!("docs")
+        .and(warp::get())
+        .map(|| warp::reply::html(SWAGGER_UI));
+
+    spec.or(docs)
+}
@@ -0,0 +1,74 @@
+use std::convert::Infallible;
+use std::time::Duration;
+use serde_json::json;
+use warp::{Filter, http::StatusCode};
+use crate::utils::with_db;
+
+/// Unauthenticated `GET /health` for load balancers and uptime monitors, plus `GET /livez` and
+/// `GET /readyz` for orchestrators that tell a running process apart from one that can serve.
+/// `/health` predates the probes and answers exactly like `/readyz`; it stays so existing
+/// monitors that rely on it failing with the database keep working.
+pub fn routes(pool: sqlx::PgPool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let health = warp::path!("health")
+        .and(warp::get())
+        .and(with_db(pool.clone()))
+        .and_then(handle_health);
+
+    let livez = warp::path!("livez")
+        .and(warp::get())
+        .map(|| warp::reply::with_status(warp::reply::json(&json!({"status": "ok"})), StatusCode::OK));
+
+    let readyz = warp::path!("readyz")
+        .and(warp::get())
+        .and(with_db(pool))
+        .and_then(handle_health);
+
+    health.or(livez).or(readyz)
+}
+
+/// 200 when the database answers a trivial query within two seconds, 503 otherwise.
+async fn handle_health(pool: sqlx::PgPool) -> Result<impl warp::Reply, Infallible> {
+    let ping = tokio::time::timeout(Duration::from_secs(2), sqlx::query!("SELECT 1 AS one").fetch_one(&pool)).await;
+
+    match ping {
+        Ok(Ok(_)) => Ok(warp::reply::with_status(warp::reply::json(&json!({"status": "ok"})), StatusCode::OK)),
+        Ok(Err(e)) => {
+            log::warn!("Health check failed: {}", crate::logging::db_error(&e));
+            Ok(warp::reply::with_status(warp::reply::json(&json!({"status": "degraded"})), StatusCode::SERVICE_UNAVAILABLE))
+        },
+        Err(_) => {
+            log::warn!("Health check timed out");
+            Ok(warp::reply::with_status(warp::reply::json(&json!({"status": "degraded"})), StatusCode::SERVICE_UNAVAILABLE))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::postgres::PgPoolOptions;
+
+    /// A pool whose database can never be reached.
+    fn unreachable_pool() -> sqlx::PgPool {
+        PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(500))
+            .connect_lazy("postgres://postgres@127.0.0.1:1/unreachable")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn readyz_is_unavailable_without_the_database() {
+        let routes = routes(unreachable_pool());
+
+        assert_eq!(warp::test::request().path("/readyz").reply(&routes).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(warp::test::request().path("/health").reply(&routes).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(warp::test::request().path("/livez").reply(&routes).await.status(), StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    async fn readyz_is_ok_with_the_database(pool: sqlx::PgPool) {
+        let routes = routes(pool);
+
+        assert_eq!(warp::test::request().path("/readyz").reply(&routes).await.status(), StatusCode::OK);
+    }
+}
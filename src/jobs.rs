@@ -0,0 +1,40 @@
+use std::time::Duration;
+use crate::db::expenses::ExpenseService;
+use crate::db::scheduled_expenses::ScheduledExpenseService;
+use crate::db::recurring_expenses::RecurringExpenseService;
+use crate::db::users::UserService;
+
+/// Spawns the once-a-day background work. The first run happens immediately at startup.
+pub fn spawn_daily(pool: sqlx::PgPool) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(24 * 60 * 60));
+        loop {
+            ticker.tick().await;
+
+            match ScheduledExpenseService::materialize_due(&pool).await {
+                Ok(count) => log::info!("Materialized {} scheduled expenses", count),
+                Err(e) => log::error!("Failed to materialize scheduled expenses: {}", crate::logging::db_error(&e)),
+            }
+
+            match RecurringExpenseService::generate_due(&pool).await {
+                Ok(count) => log::info!("Generated {} recurring expenses", count),
+                Err(e) => log::error!("Failed to generate recurring expenses: {}", crate::logging::db_error(&e)),
+            }
+
+            match ExpenseService::purge_idempotency_keys(&pool).await {
+                Ok(count) => log::info!("Purged {} expired idempotency keys", count),
+                Err(e) => log::error!("Failed to purge idempotency keys: {}", crate::logging::db_error(&e)),
+            }
+
+            match UserService::purge_token_revocations(&pool).await {
+                Ok(count) => log::info!("Purged {} expired token revocations", count),
+                Err(e) => log::error!("Failed to purge token revocations: {}", crate::logging::db_error(&e)),
+            }
+
+            match UserService::purge_password_resets(&pool).await {
+                Ok(count) => log::info!("Purged {} expired password resets", count),
+                Err(e) => log::error!("Failed to purge password resets: {}", crate::logging::db_error(&e)),
+            }
+        }
+    });
+}
@@ -0,0 +1,145 @@
+use std::collections::hash_map::RandomState;
+use std::convert::Infallible;
+use std::env;
+use std::hash::BuildHasher;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use env_logger::Env;
+use hyper::{Body, Request, Response};
+use hyper::header::HeaderValue;
+use hyper::service::Service;
+use uuid::Uuid;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Sets up `env_logger`, filtered by `RUST_LOG` (default `info`). Lines logged while handling
+/// a request carry its id after the target.
+pub fn init() {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info"))
+        .format(|buf, record| {
+            let level = buf.default_level_style(record.level());
+            let timestamp = buf.timestamp();
+            match REQUEST_ID.try_with(|id| id.clone()) {
+                Ok(id) => writeln!(buf, "[{} {level}{:<5}{level:#} {} {}] {}", timestamp, record.level(), record.target(), id, record.args()),
+                Err(_) => writeln!(buf, "[{} {level}{:<5}{level:#} {}] {}", timestamp, record.level(), record.target(), record.args()),
+            }
+        })
+        .init();
+}
+
+/// Handles `req` with its request id in scope for logging and echoes the id back in an
+/// `X-Request-Id` header. A caller-supplied `X-Request-Id` is kept when it is a short token of
+/// letters, digits, `-` and `_`; otherwise a new UUID is used. The access log can't see the
+/// client address when serving this way, so it is logged here at debug level instead.
+pub async fn with_request_id<S>(req: Request<Body>, remote_addr: SocketAddr, mut service: S) -> Result<Response<Body>, Infallible>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+{
+    let id = req
+        .headers()
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| {
+            !value.is_empty()
+                && value.len() <= 64
+                && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let mut response = REQUEST_ID.scope(id.clone(), async {
+        log::debug!("{} {} from {}", req.method(), req.uri().path(), remote_addr);
+        service.call(req).await
+    }).await?;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert("X-Request-Id", value);
+    }
+    Ok(response)
+}
+
+struct Redaction {
+    enabled: bool,
+    hasher: RandomState,
+}
+
+fn redaction() -> &'static Redaction {
+    static REDACTION: OnceLock<Redaction> = OnceLock::new();
+    REDACTION.get_or_init(|| Redaction {
+        enabled: env::var("LOG_REDACT").map(|value| value != "false").unwrap_or(true),
+        hasher: RandomState::new(),
+    })
+}
+
+impl Redaction {
+    fn id(&self, value: i32) -> String {
+        if !self.enabled {
+            return value.to_string();
+        }
+
+        format!("#{:08x}", self.hasher.hash_one(value) as u32)
+    }
+
+    fn db_error(&self, err: &sqlx::Error) -> String {
+        if !self.enabled {
+            return format!("{:?}", err);
+        }
+
+        log::debug!("database error detail: {:?}", err);
+        match err {
+            sqlx::Error::Database(db_err) => match db_err.code() {
+                Some(code) => format!("database error (code {})", code),
+                None => "database error".to_string(),
+            },
+            sqlx::Error::RowNotFound => "row not found".to_string(),
+            sqlx::Error::PoolTimedOut => "pool timed out".to_string(),
+            _ => "database error".to_string(),
+        }
+    }
+}
+
+/// Formats an identifier for logging. Unless `LOG_REDACT=false`, the id is replaced by a keyed
+/// hash that is stable for the life of the process, so lines can be correlated but not reversed.
+pub fn id(value: i32) -> String {
+    redaction().id(value)
+}
+
+/// Formats a database error for logging without query fragments or constraint details.
+/// The full error is only emitted at debug verbosity, or always when `LOG_REDACT=false`.
+pub fn db_error(err: &sqlx::Error) -> String {
+    redaction().db_error(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redaction(enabled: bool) -> Redaction {
+        Redaction { enabled, hasher: RandomState::new() }
+    }
+
+    #[test]
+    fn ids_are_hashed_when_redacting() {
+        let redaction = redaction(true);
+        let logged = redaction.id(4217);
+
+        assert!(!logged.contains("4217"));
+        assert_eq!(logged, redaction.id(4217));
+        assert_ne!(logged, redaction.id(4218));
+    }
+
+    #[test]
+    fn ids_are_plain_when_not_redacting() {
+        assert_eq!(redaction(false).id(4217), "4217");
+    }
+
+    #[test]
+    fn database_errors_leave_out_detail_when_redacting() {
+        let err = sqlx::Error::Protocol("SELECT password FROM users".into());
+
+        assert_eq!(redaction(true).db_error(&err), "database error");
+        assert!(redaction(false).db_error(&err).contains("SELECT password"));
+    }
+}
@@ -1,43 +1,131 @@
 mod db;
 mod utils;
 mod auth;
+mod logging;
+mod jobs;
+mod health;
+mod rate_limit;
+mod metrics;
+mod webhooks;
+mod docs;
+#[cfg(test)]
+mod test_support;
 
+use std::convert::Infallible;
 use std::env;
+use std::net::{IpAddr, SocketAddr};
 use dotenv::dotenv;
-use db::{budgets, expenses, users, user_budgets};
+use db::{budgets, expenses, users, user_budgets, scheduled_expenses, recurring_expenses};
 use warp::Filter;
-use env_logger::Env;
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use tokio::signal;
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
-    env_logger::init_from_env(Env::default().default_filter_or("info"));
+    logging::init();
+    auth::jwt_secret();
     let database_url = env::var("DATABASE_URL").unwrap_or_else(|_| {
         "postgres://trickyaudin@localhost:5432/ardcheese".to_string()
     });
     let auden_sylens = env::var("AUDEN_SYLENS").expect("AUDEN_SYLENS must be set");
+    let bind_addr: IpAddr = env::var("BIND_ADDR")
+        .unwrap_or_else(|_| "0.0.0.0".to_string())
+        .parse()
+        .unwrap_or_else(|e| panic!("BIND_ADDR must be an IP address such as 0.0.0.0 or ::1: {}", e));
+    let port: u16 = env::var("PORT")
+        .unwrap_or_else(|_| "2345".to_string())
+        .parse()
+        .unwrap_or_else(|e| panic!("PORT must be a number between 0 and 65535: {}", e));
 
-    let budget_service = budgets::BudgetService::new(&database_url).await;
-    let expense_service = expenses::ExpenseService::new(&database_url).await;
-    let user_service = users::UserService::new(&database_url).await;
-    let user_budget_service = user_budgets::UserBudgetService::new(&database_url).await;
+    let pool = utils::pool_options()
+        .connect(&database_url)
+        .await
+        .expect("Failed to create pool");
+    utils::run_migrations(&pool).await;
+    utils::spawn_pool_monitor("db", pool.clone());
+
+    let budget_service = budgets::BudgetService::new(pool.clone());
+    let expense_service = expenses::ExpenseService::new(pool.clone());
+    let user_service = users::UserService::new(pool.clone(), rate_limit::LoginLimiter::from_env());
+    let user_budget_service = user_budgets::UserBudgetService::new(pool.clone());
+    let scheduled_expense_service = scheduled_expenses::ScheduledExpenseService::new(pool.clone());
+    let recurring_expense_service = recurring_expenses::RecurringExpenseService::new(pool.clone());
+
+    jobs::spawn_daily(pool.clone());
+
+    let metrics = metrics::Metrics::default();
+    let recorder = metrics.clone();
 
     let cors = warp::cors()
         .allow_methods(vec!["GET", "POST", "PUT", "DELETE"])
-        .allow_headers(vec!["Content-Type", "Authorization"])
+        .allow_headers(vec!["Content-Type", "Authorization", "Idempotency-Key", "X-Request-Id"])
+        .expose_headers(vec!["X-Total-Count", "X-Next-Cursor", "X-Request-Id"])
         .allow_origins(vec![
             "https://ardfudge.ardmore.us",
             auden_sylens.as_str()
         ]);
 
-    let routes = budget_service.routes()
+    let api = budget_service.routes()
         .or(expense_service.routes()
             .or(user_service.routes()
-                .or(user_budget_service.routes())))
+                .or(user_budget_service.routes()
+                    .or(scheduled_expense_service.routes()
+                        .or(recurring_expense_service.routes())))));
+
+    // Unversioned paths remain as deprecated aliases of /v1. The health check and metrics sit
+    // outside versioning so probes and scrapers keep working across API versions, and the API
+    // docs sit beside them.
+    let routes = utils::compressed(utils::trailing_slash()
+        .or(utils::base_path()
+            .and(health::routes(pool.clone())
+                .or(metrics::routes(metrics, pool.clone()))
+                .or(docs::routes())
+                .or(warp::path("v1").and(api.clone()))
+                .or(utils::deprecated(api, env::var("UNVERSIONED_SUNSET").ok()))))
+        .recover(utils::handle_rejection))
         .with(cors)
-        .with(warp::log("api"));
+        .with(warp::log("api"))
+        .with(warp::log::custom(move |info| recorder.record(info.method(), info.path(), info.status())));
+
+    // Served through hyper directly so each request runs with its id in scope for logging.
+    let service = warp::service(routes);
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let service = service.clone();
+        let remote_addr = conn.remote_addr();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| logging::with_request_id(req, remote_addr, service.clone())))
+        }
+    });
+
+    let addr = SocketAddr::new(bind_addr, port);
+    let server = hyper::Server::try_bind(&addr)
+        .unwrap_or_else(|e| panic!("Failed to bind {}: {}", addr, e))
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown_signal());
+    log::info!("Listening on {}", addr);
+    if let Err(e) = server.await {
+        log::error!("Server error: {}", e);
+    }
+
+    pool.close().await;
+    log::info!("Database pool closed");
+}
+
+/// Resolves on Ctrl-C or SIGTERM. The server then stops accepting connections and finishes
+/// in-flight requests before `main` closes the pool.
+async fn shutdown_signal() {
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
 
-    warp::serve(routes)
-        .run(([0, 0, 0, 0], 2345))
-        .await;
+    tokio::select! {
+        _ = signal::ctrl_c() => {},
+        _ = terminate => {},
+    }
+    log::info!("Shutting down");
 }
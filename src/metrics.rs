@@ -0,0 +1,163 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use warp::Filter;
+use warp::http::{Method, StatusCode};
+use crate::utils::with_db;
+
+/// Distinct method and route labels kept before new ones fold into `unmatched`. Comfortably
+/// above the number of routes served, versioned and unversioned.
+const MAX_ROUTES: usize = 512;
+
+/// Request counters shared by the logging filter that updates them and `GET /metrics`.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Counters>,
+}
+
+#[derive(Default)]
+struct Counters {
+    requests: AtomicU64,
+    /// Responses by status class, `1xx` through `5xx`.
+    classes: [AtomicU64; 5],
+    routes: Mutex<BTreeMap<(String, String), u64>>,
+}
+
+impl Metrics {
+    /// Counts a finished request. Numeric path segments are folded into `{id}`. Responses warp
+    /// gives when no route takes a request (404, 405, 411, 413, 415 and the trailing-slash 308)
+    /// share one `unmatched` label, as does anything past `MAX_ROUTES` labels, and extension
+    /// methods count as `OTHER`, so clients can't grow the route table without bound.
+    pub fn record(&self, method: &Method, path: &str, status: StatusCode) {
+        self.inner.requests.fetch_add(1, Ordering::Relaxed);
+        if let Some(class) = self.inner.classes.get(status.as_u16() as usize / 100 - 1) {
+            class.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let unmatched = [
+            StatusCode::NOT_FOUND,
+            StatusCode::METHOD_NOT_ALLOWED,
+            StatusCode::LENGTH_REQUIRED,
+            StatusCode::PAYLOAD_TOO_LARGE,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            StatusCode::PERMANENT_REDIRECT,
+        ];
+        let standard = [
+            Method::GET, Method::POST, Method::PUT, Method::DELETE, Method::PATCH,
+            Method::HEAD, Method::OPTIONS, Method::CONNECT, Method::TRACE,
+        ];
+        let method = if standard.contains(method) { method.as_str() } else { "OTHER" };
+        let mut key = (method.to_string(), "unmatched".to_string());
+        if !unmatched.contains(&status) {
+            key.1 = path.split('/')
+                .map(|segment| if !segment.is_empty() && segment.bytes().all(|b| b.is_ascii_digit()) { "{id}" } else { segment })
+                .collect::<Vec<_>>()
+                .join("/");
+        }
+
+        let mut routes = self.inner.routes.lock().unwrap();
+        if routes.len() >= MAX_ROUTES && !routes.contains_key(&key) {
+            key.1 = "unmatched".to_string();
+        }
+        *routes.entry(key).or_insert(0) += 1;
+    }
+
+    /// The counters and `pool`'s connection gauges in Prometheus text exposition format.
+    fn render(&self, pool: &sqlx::PgPool) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP ardpie_http_requests_total Requests served.\n");
+        out.push_str("# TYPE ardpie_http_requests_total counter\n");
+        let _ = writeln!(out, "ardpie_http_requests_total {}", self.inner.requests.load(Ordering::Relaxed));
+
+        out.push_str("# HELP ardpie_http_responses_total Responses by status class.\n");
+        out.push_str("# TYPE ardpie_http_responses_total counter\n");
+        for (i, class) in self.inner.classes.iter().enumerate() {
+            let _ = writeln!(out, "ardpie_http_responses_total{{class=\"{}xx\"}} {}", i + 1, class.load(Ordering::Relaxed));
+        }
+
+        out.push_str("# HELP ardpie_http_route_requests_total Requests by method and route.\n");
+        out.push_str("# TYPE ardpie_http_route_requests_total counter\n");
+        for ((method, route), count) in self.inner.routes.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "ardpie_http_route_requests_total{{method=\"{}\",route=\"{}\"}} {}",
+                method,
+                route.replace('\\', "\\\\").replace('"', "\\\""),
+                count
+            );
+        }
+
+        let size = pool.size();
+        let idle = pool.num_idle() as u32;
+        out.push_str("# HELP ardpie_db_pool_connections Open database connections by state.\n");
+        out.push_str("# TYPE ardpie_db_pool_connections gauge\n");
+        let _ = writeln!(out, "ardpie_db_pool_connections{{state=\"idle\"}} {}", idle);
+        let _ = writeln!(out, "ardpie_db_pool_connections{{state=\"in_use\"}} {}", size.saturating_sub(idle));
+        out.push_str("# HELP ardpie_db_pool_max_connections Configured pool capacity.\n");
+        out.push_str("# TYPE ardpie_db_pool_max_connections gauge\n");
+        let _ = writeln!(out, "ardpie_db_pool_max_connections {}", pool.options().get_max_connections());
+
+        out
+    }
+}
+
+/// Unauthenticated `GET /metrics`. Set `METRICS_ENABLED=false` to leave it unrouted.
+pub fn routes(metrics: Metrics, pool: sqlx::PgPool) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let enabled = env::var("METRICS_ENABLED").map(|value| value != "false").unwrap_or(true);
+
+    warp::path!("metrics")
+        .and(warp::get())
+        .and_then(move || async move {
+            if enabled { Ok(()) } else { Err(warp::reject::not_found()) }
+        })
+        .untuple_one()
+        .and(warp::any().map(move || metrics.clone()))
+        .and(with_db(pool))
+        .map(|metrics: Metrics, pool: sqlx::PgPool| {
+            warp::reply::with_header(metrics.render(&pool), "Content-Type", "text/plain; version=0.0.4")
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route_count(metrics: &Metrics) -> usize {
+        metrics.inner.routes.lock().unwrap().len()
+    }
+
+    #[test]
+    fn record_folds_ids_and_unmatched_paths() {
+        let metrics = Metrics::default();
+        metrics.record(&Method::GET, "/v1/budgets/12/closing", StatusCode::OK);
+        metrics.record(&Method::GET, "/v1/budgets/34/closing", StatusCode::UNAUTHORIZED);
+        for status in [StatusCode::NOT_FOUND, StatusCode::METHOD_NOT_ALLOWED, StatusCode::LENGTH_REQUIRED] {
+            metrics.record(&Method::POST, "/users/foo", status);
+        }
+
+        let routes = metrics.inner.routes.lock().unwrap();
+        assert_eq!(routes.get(&("GET".to_string(), "/v1/budgets/{id}/closing".to_string())), Some(&2));
+        assert_eq!(routes.get(&("POST".to_string(), "unmatched".to_string())), Some(&3));
+        assert_eq!(routes.len(), 2);
+    }
+
+    #[test]
+    fn record_keeps_the_route_table_bounded() {
+        let metrics = Metrics::default();
+        for i in 0..5000 {
+            let status = [StatusCode::OK, StatusCode::BAD_REQUEST, StatusCode::METHOD_NOT_ALLOWED][i % 3];
+            metrics.record(&Method::DELETE, &format!("/v1/users/rand{}/deep/path{}", i, i), status);
+        }
+
+        for i in 0..100 {
+            let method = Method::from_bytes(format!("X{}", i).as_bytes()).unwrap();
+            metrics.record(&method, "/users/foo", StatusCode::METHOD_NOT_ALLOWED);
+        }
+
+        assert!(route_count(&metrics) <= MAX_ROUTES + 2);
+        assert_eq!(metrics.inner.requests.load(Ordering::Relaxed), 5100);
+    }
+}
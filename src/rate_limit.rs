@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use warp::Filter;
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_WINDOW_SECS: u64 = 900;
+
+/// Counts failed logins per username. Once `LOGIN_MAX_ATTEMPTS` failures land within
+/// `LOGIN_WINDOW_SECS` of the first one, further attempts are refused until the window passes.
+/// A successful login clears the count.
+#[derive(Clone)]
+pub struct LoginLimiter {
+    attempts: Arc<Mutex<HashMap<String, (u32, Instant)>>>,
+    max_attempts: u32,
+    window: Duration,
+}
+
+impl LoginLimiter {
+    pub fn from_env() -> Self {
+        let max_attempts = match env::var("LOGIN_MAX_ATTEMPTS") {
+            Ok(value) => match value.parse::<u32>() {
+                Ok(max) if max > 0 => max,
+                _ => {
+                    log::warn!("LOGIN_MAX_ATTEMPTS must be a positive number, using default of {}", DEFAULT_MAX_ATTEMPTS);
+                    DEFAULT_MAX_ATTEMPTS
+                },
+            },
+            Err(_) => DEFAULT_MAX_ATTEMPTS,
+        };
+        let window_secs = match env::var("LOGIN_WINDOW_SECS") {
+            Ok(value) => match value.parse::<u64>() {
+                Ok(secs) if secs > 0 => secs,
+                _ => {
+                    log::warn!("LOGIN_WINDOW_SECS must be a positive number of seconds, using default of {}", DEFAULT_WINDOW_SECS);
+                    DEFAULT_WINDOW_SECS
+                },
+            },
+            Err(_) => DEFAULT_WINDOW_SECS,
+        };
+
+        LoginLimiter {
+            attempts: Arc::new(Mutex::new(HashMap::new())),
+            max_attempts,
+            window: Duration::from_secs(window_secs),
+        }
+    }
+
+    pub fn is_blocked(&self, key: &str) -> bool {
+        let attempts = self.attempts.lock().unwrap();
+        match attempts.get(key) {
+            Some((count, started)) => *count >= self.max_attempts && started.elapsed() < self.window,
+            None => false,
+        }
+    }
+
+    /// Expired entries are dropped here so the map only holds names with recent failures.
+    pub fn record_failure(&self, key: &str) {
+        let mut attempts = self.attempts.lock().unwrap();
+        attempts.retain(|_, (_, started)| started.elapsed() < self.window);
+
+        let entry = attempts.entry(key.to_string()).or_insert((0, Instant::now()));
+        entry.0 += 1;
+    }
+
+    pub fn reset(&self, key: &str) {
+        self.attempts.lock().unwrap().remove(key);
+    }
+}
+
+pub fn with_limiter(limiter: LoginLimiter) -> impl Filter<Extract = (LoginLimiter,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || limiter.clone())
+}
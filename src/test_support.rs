@@ -0,0 +1,63 @@
+//! Fixtures shared by the tests. Database tests run under `#[sqlx::test]`, which gives each
+//! test a fresh database with the migrations applied and so needs `DATABASE_URL` to point at a
+//! server where it may create databases.
+
+use std::sync::Once;
+use jsonwebtoken::{encode, EncodingKey, Header};
+use crate::auth::{jwt_secret, Claims};
+
+/// Signs a token for `user_id` valid for an hour, setting a test `JWT_SECRET` first.
+pub fn token(user_id: i32) -> String {
+    static SECRET: Once = Once::new();
+    SECRET.call_once(|| std::env::set_var("JWT_SECRET", "test-secret"));
+
+    let claims = Claims {
+        user_id,
+        name: String::new(),
+        exp: (time::OffsetDateTime::now_utc().unix_timestamp() + 3600) as usize,
+        jti: None,
+    };
+    encode(&Header::default(), &claims, &EncodingKey::from_secret(jwt_secret().as_ref())).expect("failed to sign test token")
+}
+
+/// The `Authorization` header value for `user_id`.
+pub fn bearer(user_id: i32) -> String {
+    format!("Bearer {}", token(user_id))
+}
+
+/// Adds a user called `name`.
+pub async fn user(pool: &sqlx::PgPool, name: &str) -> i32 {
+    sqlx::query_scalar("INSERT INTO users (name, password) VALUES ($1, 'not-a-hash') RETURNING id")
+        .bind(name)
+        .fetch_one(pool)
+        .await
+        .expect("failed to insert test user")
+}
+
+/// Adds a budget owned by `owner`.
+pub async fn budget(pool: &sqlx::PgPool, owner: i32) -> i32 {
+    let id: i32 = sqlx::query_scalar("INSERT INTO budgets (name) VALUES ('Test budget') RETURNING id")
+        .fetch_one(pool)
+        .await
+        .expect("failed to insert test budget");
+
+    sqlx::query("INSERT INTO user_budgets (userid, budgetid, role) VALUES ($1, $2, 'owner')")
+        .bind(owner)
+        .bind(id)
+        .execute(pool)
+        .await
+        .expect("failed to insert test budget owner");
+
+    id
+}
+
+/// Adds an expense of `amount` on `date` (`YYYY-MM-DD`) to `budget`.
+pub async fn expense(pool: &sqlx::PgPool, budget: i32, date: &str, amount: &str) -> i32 {
+    sqlx::query_scalar("INSERT INTO expenses (budgetid, date, description, amount) VALUES ($1, $2::DATE, 'Test expense', $3::NUMERIC) RETURNING id")
+        .bind(budget)
+        .bind(date)
+        .bind(amount)
+        .fetch_one(pool)
+        .await
+        .expect("failed to insert test expense")
+}
@@ -1,11 +1,29 @@
 use warp::Filter;
+use warp::filters::BoxedFilter;
+use warp::http::{HeaderValue, StatusCode};
+use warp::path::FullPath;
+use warp::Reply;
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::env;
 use std::fmt;
+use std::sync::OnceLock;
+use std::time::Duration;
+use sqlx::migrate::Migrate;
+use sqlx::postgres::PgPoolOptions;
+use time::{Date, Month};
+use bigdecimal::{BigDecimal, RoundingMode};
+use serde::Serialize;
+use crate::auth::AuthError;
+use crate::logging;
 
 #[derive(Debug)]
 pub enum ServiceError {
     Unauthorized,
     DatabaseError(sqlx::Error),
+    NotFound,
     BadRequest(String),
+    Conflict(String),
     InternalServerError,
 }
 
@@ -14,7 +32,9 @@ impl fmt::Display for ServiceError {
         match self {
             ServiceError::Unauthorized => write!(f, "Unauthorized access"),
             ServiceError::DatabaseError(_) => write!(f, "Database error occurred"),
+            ServiceError::NotFound => write!(f, "Not found"),
             ServiceError::BadRequest(detail) => write!(f, "Bad request: {}", detail),
+            ServiceError::Conflict(detail) => write!(f, "Conflict: {}", detail),
             ServiceError::InternalServerError => write!(f, "Internal server error"),
         }
     }
@@ -22,35 +42,867 @@ impl fmt::Display for ServiceError {
 
 impl warp::reject::Reject for ServiceError {}
 
+impl From<sqlx::Error> for ServiceError {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::RowNotFound => ServiceError::NotFound,
+            err => ServiceError::DatabaseError(err),
+        }
+    }
+}
+
+/// Rejects with the `ServiceError` for a failed query, so a missing row answers 404 rather
+/// than 500.
+pub fn db_rejection(err: sqlx::Error) -> warp::Rejection {
+    warp::reject::custom(ServiceError::from(err))
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+    code: &'static str,
+}
+
+/// Turns any rejection into a `{"error": ..., "code": ...}` body with a matching status, so
+/// clients see one error shape regardless of which filter or handler failed.
+pub async fn handle_rejection(err: warp::Rejection) -> Result<impl Reply, Infallible> {
+    let (status, code, error) = if err.is_not_found() {
+        (StatusCode::NOT_FOUND, "not_found", "Not found".to_string())
+    } else if let Some(e) = err.find::<ServiceError>() {
+        match e {
+            ServiceError::Unauthorized => (StatusCode::UNAUTHORIZED, "unauthorized", e.to_string()),
+            ServiceError::DatabaseError(db_err) => {
+                log::error!("Request failed: {}", logging::db_error(db_err));
+                (StatusCode::INTERNAL_SERVER_ERROR, "database_error", e.to_string())
+            },
+            ServiceError::NotFound => (StatusCode::NOT_FOUND, "not_found", e.to_string()),
+            ServiceError::BadRequest(_) => (StatusCode::BAD_REQUEST, "bad_request", e.to_string()),
+            ServiceError::Conflict(_) => (StatusCode::CONFLICT, "conflict", e.to_string()),
+            ServiceError::InternalServerError => (StatusCode::INTERNAL_SERVER_ERROR, "internal_error", e.to_string()),
+        }
+    } else if let Some(e) = err.find::<AuthError>() {
+        let code = match e {
+            AuthError::Missing => "missing_token",
+            AuthError::Invalid => "invalid_token",
+            AuthError::Expired => "expired_token",
+            AuthError::Revoked => "revoked_token",
+        };
+        (StatusCode::UNAUTHORIZED, code, e.to_string())
+    } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, "invalid_body", e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, "invalid_query", e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::UnsupportedMediaType>() {
+        (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type", e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::PayloadTooLarge>() {
+        (StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::LengthRequired>() {
+        (StatusCode::LENGTH_REQUIRED, "length_required", e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::MethodNotAllowed>() {
+        (StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", e.to_string())
+    } else {
+        log::error!("Unhandled rejection: {:?}", err);
+        (StatusCode::INTERNAL_SERVER_ERROR, "internal_error", "Internal server error".to_string())
+    };
+
+    Ok(warp::reply::with_status(warp::reply::json(&ErrorBody { error, code }), status))
+}
+
+/// Reads a number of seconds from `key`, falling back to `default` when unset or malformed.
+/// A value of `0` disables the setting.
+fn env_secs(key: &str, default: u64) -> Option<Duration> {
+    parse_secs(key, env::var(key).ok().as_deref(), default)
+}
+
+/// `env_secs` for a `value` already read from `key`.
+fn parse_secs(key: &str, value: Option<&str>, default: u64) -> Option<Duration> {
+    let secs = match value {
+        Some(value) => value.parse::<u64>().unwrap_or_else(|_| {
+            log::warn!("{} must be a whole number of seconds, using default of {}", key, default);
+            default
+        }),
+        None => default,
+    };
+
+    if secs == 0 { None } else { Some(Duration::from_secs(secs)) }
+}
+
+/// Options for the pool every service shares. It holds up to `DB_MAX_CONNECTIONS` connections
+/// (default 10), reaps idle ones after `DB_IDLE_TIMEOUT_SECS` and recycles all of them after
+/// `DB_MAX_LIFETIME_SECS`.
+pub fn pool_options() -> PgPoolOptions {
+    pool_options_from(
+        env::var("DB_MAX_CONNECTIONS").ok().as_deref(),
+        env::var("DB_IDLE_TIMEOUT_SECS").ok().as_deref(),
+        env::var("DB_MAX_LIFETIME_SECS").ok().as_deref(),
+    )
+}
+
+/// `pool_options` for the given values of its three variables.
+fn pool_options_from(max_connections: Option<&str>, idle_timeout: Option<&str>, max_lifetime: Option<&str>) -> PgPoolOptions {
+    let max_connections = max_connections
+        .and_then(|value| value.parse::<u32>().ok())
+        .unwrap_or(10);
+
+    PgPoolOptions::new()
+        .max_connections(max_connections)
+        .idle_timeout(parse_secs("DB_IDLE_TIMEOUT_SECS", idle_timeout, 600))
+        .max_lifetime(parse_secs("DB_MAX_LIFETIME_SECS", max_lifetime, 1800))
+}
+
+/// Applies any migrations in `migrations/` the database hasn't seen, logging each one. Startup
+/// can't continue against a schema that is behind the code, so a failure is fatal.
+pub async fn run_migrations(pool: &sqlx::PgPool) {
+    let migrator = sqlx::migrate!();
+
+    let applied: HashSet<i64> = async {
+        let mut conn = pool.acquire().await?;
+        conn.ensure_migrations_table().await?;
+        Ok::<_, sqlx::migrate::MigrateError>(conn.list_applied_migrations().await?.into_iter().map(|m| m.version).collect())
+    }
+        .await
+        .unwrap_or_else(|e| panic!("Could not read applied database migrations: {}", e));
+
+    if let Err(e) = migrator.run(pool).await {
+        panic!("Database migration failed, refusing to start: {}", e);
+    }
+
+    let mut count = 0;
+    for migration in migrator.iter().filter(|m| !applied.contains(&m.version)) {
+        log::info!("Applied migration {} {}", migration.version, migration.description);
+        count += 1;
+    }
+    log::info!("Database schema up to date ({} migrations applied)", count);
+}
+
+/// Periodically logs pool size, idle and in-use connections every `DB_POOL_STATS_INTERVAL_SECS`.
+pub fn spawn_pool_monitor(label: &'static str, pool: sqlx::PgPool) {
+    let interval = match env_secs("DB_POOL_STATS_INTERVAL_SECS", 60) {
+        Some(interval) => interval,
+        None => return,
+    };
+
+    log::info!(
+        "{} pool: max_connections={} idle_timeout={:?} max_lifetime={:?}",
+        label,
+        pool.options().get_max_connections(),
+        pool.options().get_idle_timeout(),
+        pool.options().get_max_lifetime()
+    );
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if pool.is_closed() {
+                break;
+            }
+
+            let size = pool.size();
+            let idle = pool.num_idle() as u32;
+            log::info!("{} pool: size={} idle={} in_use={}", label, size, idle, size.saturating_sub(idle));
+        }
+    });
+}
+
+/// Parses a `YYYY-MM` string into the first and last day of that month.
+pub fn month_bounds(month: &str) -> Option<(Date, Date)> {
+    let (year, month) = month.split_once('-')?;
+    calendar_month(year.parse().ok()?, month.parse().ok()?)
+}
+
+/// The first and last day of `month` (1 to 12) in `year`.
+pub fn calendar_month(year: i32, month: u8) -> Option<(Date, Date)> {
+    let month = Month::try_from(month).ok()?;
+
+    let start = Date::from_calendar_date(year, month, 1).ok()?;
+    let next_year = if month == Month::December { year + 1 } else { year };
+    let end = Date::from_calendar_date(next_year, month.next(), 1).ok()?.previous_day()?;
+
+    Some((start, end))
+}
+
+/// Parses a `YYYY-MM-DD` string.
+pub fn parse_date(date: &str) -> Option<Date> {
+    let mut parts = date.splitn(3, '-');
+    let (year, month, day) = (parts.next()?, parts.next()?, parts.next()?);
+    if month.len() != 2 || day.len() != 2 {
+        return None;
+    }
+    Date::from_calendar_date(year.parse().ok()?, Month::try_from(month.parse::<u8>().ok()?).ok()?, day.parse().ok()?).ok()
+}
+
+/// Rejects date windows whose `end_date` falls before `start_date`.
+pub fn validate_date_range(start_date: Date, end_date: Option<Date>) -> Result<(), warp::Rejection> {
+    match end_date {
+        Some(end_date) if end_date < start_date => Err(warp::reject::custom(ServiceError::BadRequest(
+            "end_date must not be before start_date".into(),
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Checks that `url`, given as the `field` setting, is an absolute `http` or `https` URL with a
+/// host, of at most 2048 characters.
+pub fn validate_http_url(field: &str, url: &str) -> Result<(), String> {
+    if url.len() > 2048 {
+        return Err(format!("{} must be at most 2048 characters", field));
+    }
+
+    let invalid = || format!("{} must be an http or https URL", field);
+    let uri = url.parse::<warp::http::Uri>().map_err(|_| invalid())?;
+    match (uri.scheme_str(), uri.host()) {
+        (Some("http" | "https"), Some(host)) if !host.is_empty() => Ok(()),
+        _ => Err(invalid()),
+    }
+}
+
+/// Escapes `\`, `%` and `_` so user input matches literally inside a `LIKE` pattern.
+pub fn escape_like(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Parses a comma-separated list of ids such as `1,2,3`.
+pub fn parse_id_list(ids: &str) -> Result<Vec<i32>, warp::Rejection> {
+    ids.split(',')
+        .map(|id| id.trim().parse::<i32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| warp::reject::custom(ServiceError::BadRequest(format!("Invalid id list: {}", ids))))
+}
+
+const CUSTOM_FIELD_TYPES: [&str; 3] = ["string", "number", "boolean"];
+
+/// Checks that `settings.custom_fields`, if present, maps field names to a supported type.
+pub fn validate_custom_fields(settings: &serde_json::Value) -> Result<(), String> {
+    let fields = match settings.get("custom_fields") {
+        None | Some(serde_json::Value::Null) => return Ok(()),
+        Some(serde_json::Value::Object(fields)) => fields,
+        Some(_) => return Err("custom_fields must be an object of field names to types".into()),
+    };
+
+    for (name, kind) in fields {
+        match kind.as_str() {
+            Some(kind) if CUSTOM_FIELD_TYPES.contains(&kind) => {},
+            _ => return Err(format!("custom field {} must have a type of {}", name, CUSTOM_FIELD_TYPES.join(", "))),
+        }
+    }
+
+    Ok(())
+}
+
+/// Number formatting for each supported budget `locale`: decimal separator, thousands separator,
+/// and whether the currency symbol goes before or after the amount.
+const LOCALES: [(&str, &str, &str, &str); 10] = [
+    ("en-US", ".", ",", "before"),
+    ("en-GB", ".", ",", "before"),
+    ("en-IN", ".", ",", "before"),
+    ("de-DE", ",", ".", "after"),
+    ("de-CH", ".", "\u{2019}", "before"),
+    ("es-ES", ",", ".", "after"),
+    ("fr-FR", ",", "\u{202f}", "after"),
+    ("it-IT", ",", ".", "after"),
+    ("pt-BR", ",", ".", "before"),
+    ("ja-JP", ".", ",", "before"),
+];
+
+pub const DEFAULT_LOCALE: &str = "en-US";
+
+/// Formatting conventions for `locale` as (decimal separator, thousands separator, symbol position).
+pub fn locale_format(locale: &str) -> Option<(&'static str, &'static str, &'static str)> {
+    LOCALES
+        .iter()
+        .find(|(name, ..)| *name == locale)
+        .map(|(_, decimal, thousands, position)| (*decimal, *thousands, *position))
+}
+
+/// Checks that `settings.locale`, if present, is one of the supported locales.
+pub fn validate_locale(settings: &serde_json::Value) -> Result<(), String> {
+    match settings.get("locale") {
+        None | Some(serde_json::Value::Null) => Ok(()),
+        Some(serde_json::Value::String(locale)) if locale_format(locale).is_some() => Ok(()),
+        Some(_) => Err(format!(
+            "locale must be one of {}",
+            LOCALES.iter().map(|(name, ..)| *name).collect::<Vec<_>>().join(", ")
+        )),
+    }
+}
+
+/// ISO 4217 codes a budget can be kept in, with the symbol clients show next to amounts.
+const CURRENCIES: [(&str, &str); 16] = [
+    ("USD", "$"),
+    ("EUR", "\u{20ac}"),
+    ("GBP", "\u{a3}"),
+    ("JPY", "\u{a5}"),
+    ("CNY", "\u{a5}"),
+    ("INR", "\u{20b9}"),
+    ("CHF", "CHF"),
+    ("CAD", "CA$"),
+    ("AUD", "A$"),
+    ("NZD", "NZ$"),
+    ("MXN", "MX$"),
+    ("BRL", "R$"),
+    ("SEK", "kr"),
+    ("NOK", "kr"),
+    ("DKK", "kr"),
+    ("KRW", "\u{20a9}"),
+];
+
+/// The symbol for a supported currency code.
+pub fn currency_symbol(code: &str) -> Option<&'static str> {
+    CURRENCIES.iter().find(|(c, _)| *c == code).map(|(_, symbol)| *symbol)
+}
+
+/// Digits after the decimal point that amounts in `code` are kept to.
+pub fn currency_scale(code: &str) -> i64 {
+    match code {
+        "JPY" | "KRW" => 0,
+        _ => 2,
+    }
+}
+
+/// Rounds `amount` half away from zero to the scale of `currency`, so equal amounts always
+/// carry the same digits.
+pub fn round_amount(amount: &BigDecimal, currency: &str) -> BigDecimal {
+    amount.with_scale_round(currency_scale(currency), RoundingMode::HalfUp)
+}
+
+/// Checks that a currency code is one of the supported ones.
+pub fn validate_currency(code: &str) -> Result<(), String> {
+    match currency_symbol(code) {
+        Some(_) => Ok(()),
+        None => Err(format!(
+            "currency must be one of {}",
+            CURRENCIES.iter().map(|(code, _)| *code).collect::<Vec<_>>().join(", ")
+        )),
+    }
+}
+
+/// The currency of budgets that don't set one: `DEFAULT_CURRENCY` if supported, otherwise USD.
+pub fn default_currency() -> &'static str {
+    static DEFAULT: OnceLock<&'static str> = OnceLock::new();
+    DEFAULT.get_or_init(|| {
+        let configured = env::var("DEFAULT_CURRENCY").unwrap_or_else(|_| "USD".to_string());
+        match CURRENCIES.iter().find(|(code, _)| *code == configured) {
+            Some((code, _)) => code,
+            None => {
+                log::warn!("DEFAULT_CURRENCY {} is not supported, using USD", configured);
+                "USD"
+            },
+        }
+    })
+}
+
+/// A budget's currency code and symbol from `settings.currency`. Budgets without one, or with
+/// a code saved before currencies were validated, report the default.
+pub fn budget_currency(settings: &serde_json::Value) -> (&'static str, &'static str) {
+    let find = |code: &str| CURRENCIES.iter().find(|(c, _)| *c == code);
+    settings
+        .get("currency")
+        .and_then(|c| c.as_str())
+        .and_then(find)
+        .or_else(|| find(default_currency()))
+        .map(|(code, symbol)| (*code, *symbol))
+        .expect("default currency is supported")
+}
+
+/// The ISO days of the week (1 = Monday through 7 = Sunday) that per-day figures divide by, from
+/// `settings.count_days`: `"all"` (the default), `"weekdays"`, or an array of ISO day numbers.
+pub fn count_days(settings: &serde_json::Value) -> Result<Vec<i32>, String> {
+    const INVALID: &str = "count_days must be \"all\", \"weekdays\" or a non-empty array of ISO weekdays (1-7)";
+
+    match settings.get("count_days") {
+        None | Some(serde_json::Value::Null) => Ok((1..=7).collect()),
+        Some(serde_json::Value::String(mode)) if mode == "all" => Ok((1..=7).collect()),
+        Some(serde_json::Value::String(mode)) if mode == "weekdays" => Ok((1..=5).collect()),
+        Some(serde_json::Value::Array(days)) if !days.is_empty() => {
+            let mut days = days
+                .iter()
+                .map(|day| day.as_i64().filter(|day| (1..=7).contains(day)).map(|day| day as i32))
+                .collect::<Option<Vec<_>>>()
+                .ok_or(INVALID)?;
+            days.sort_unstable();
+            days.dedup();
+            Ok(days)
+        },
+        Some(_) => Err(INVALID.into()),
+    }
+}
+
+/// Checks an expense's `custom_data` against the custom fields defined in its budget's settings.
+pub fn validate_custom_data(settings: &serde_json::Value, data: &serde_json::Value) -> Result<(), String> {
+    let data = data.as_object().ok_or("custom_data must be an object")?;
+    let fields = settings.get("custom_fields").and_then(|fields| fields.as_object());
+
+    for (name, value) in data {
+        let kind = fields
+            .and_then(|fields| fields.get(name))
+            .and_then(|kind| kind.as_str())
+            .ok_or_else(|| format!("{} is not a custom field of this budget", name))?;
+
+        let matches = match kind {
+            "string" => value.is_string(),
+            "number" => value.is_number(),
+            "boolean" => value.is_boolean(),
+            _ => false,
+        };
+
+        if !matches && !value.is_null() {
+            return Err(format!("custom field {} must be a {}", name, kind));
+        }
+    }
+
+    Ok(())
+}
+
+/// Matches the path segments of `BASE_PATH` (e.g. `/api/v1`), or nothing when it is unset.
+pub fn base_path() -> BoxedFilter<()> {
+    path_prefix(&env::var("BASE_PATH").unwrap_or_default())
+}
+
+/// Matches the segments of `prefix`, ignoring empty ones, so `/api/v1/` and `api/v1` agree.
+fn path_prefix(prefix: &str) -> BoxedFilter<()> {
+    prefix
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .fold(warp::any().boxed(), |filter, segment| {
+            filter.and(warp::path(segment.to_string())).boxed()
+        })
+}
+
+/// Handles request paths that end in a slash, per `TRAILING_SLASH`. The default, `redirect`,
+/// answers `/expenses/?x=1` with a 308 to `/expenses?x=1`; `strict` lets them fall through to a 404.
+pub fn trailing_slash() -> BoxedFilter<(impl Reply,)> {
+    let redirect = env::var("TRAILING_SLASH").map_or(true, |mode| mode != "strict");
+    warp::path::full()
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and_then(move |path: FullPath, query: String| async move {
+            let path = path.as_str();
+            if !redirect || path == "/" || !path.ends_with('/') {
+                return Err(warp::reject::not_found());
+            }
+
+            let mut location = path.trim_end_matches('/').to_string();
+            if location.is_empty() {
+                location.push('/');
+            }
+            if !query.is_empty() {
+                location.push('?');
+                location.push_str(&query);
+            }
+            Ok(warp::reply::with_header(StatusCode::PERMANENT_REDIRECT, "Location", location))
+        })
+        .boxed()
+}
+
+/// Marks every response from `filter` with a `Deprecation` header and, when a removal date
+/// (an HTTP date such as `Wed, 01 Jan 2025 00:00:00 GMT`) is given, a `Sunset` header. Wrapping
+/// a single route gives it its own date: responses already marked keep their headers, so a
+/// route's own marking wins over one applied to a whole group such as the unversioned aliases.
+pub fn deprecated<F, R>(filter: F, sunset: Option<String>) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone
+where
+    F: Filter<Extract = (R,), Error = warp::Rejection> + Clone,
+    R: Reply,
+{
+    let sunset = sunset.and_then(|sunset| HeaderValue::from_str(&sunset).ok());
+    filter.map(move |reply: R| {
+        let mut response = reply.into_response();
+        let headers = response.headers_mut();
+        if headers.contains_key("Deprecation") {
+            return response;
+        }
+
+        headers.insert("Deprecation", HeaderValue::from_static("true"));
+        if let Some(sunset) = &sunset {
+            headers.insert("Sunset", sunset.clone());
+        }
+        response
+    })
+}
+
+/// Passes when the request's `Accept-Encoding` allows `encoding`, either by name or through
+/// `*`, with a non-zero quality.
+fn accepts_encoding(encoding: &'static str) -> BoxedFilter<()> {
+    warp::header::optional::<String>("accept-encoding")
+        .and_then(move |accept: Option<String>| async move {
+            let accepted = accept.unwrap_or_default().split(',').any(|item| {
+                let mut parts = item.split(';').map(str::trim);
+                let name = parts.next().unwrap_or_default();
+                let zero_quality = parts.any(|param| {
+                    param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+                });
+                (name.eq_ignore_ascii_case(encoding) || name == "*") && !zero_quality
+            });
+            if accepted { Ok(()) } else { Err(warp::reject::not_found()) }
+        })
+        .untuple_one()
+        .boxed()
+}
+
+/// Compresses every response from `filter` with gzip, or deflate when only that is accepted,
+/// according to the client's `Accept-Encoding`. `filter` must not reject, so a request is only
+/// ever handled once whichever branch serves it.
+pub fn compressed<F, R>(filter: F) -> BoxedFilter<(warp::reply::Response,)>
+where
+    F: Filter<Extract = (R,), Error = Infallible> + Clone + Send + Sync + 'static,
+    R: Reply + 'static,
+{
+    // Boxed so the route tree isn't instantiated once per branch.
+    let filter = filter.map(Reply::into_response).boxed();
+    let vary = |mut response: warp::reply::Response| {
+        response.headers_mut().append("Vary", HeaderValue::from_static("Accept-Encoding"));
+        response
+    };
+
+    accepts_encoding("gzip")
+        .and(filter.clone())
+        .with(warp::filters::compression::gzip())
+        .map(Reply::into_response)
+        .or(accepts_encoding("deflate")
+            .and(filter.clone())
+            .with(warp::filters::compression::deflate())
+            .map(Reply::into_response))
+        .unify()
+        .or(filter)
+        .unify()
+        .map(vary)
+        .boxed()
+}
+
 pub fn with_db(pool: sqlx::PgPool) -> impl Filter<Extract = (sqlx::PgPool,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || pool.clone())
 }
 
 
+const DEFAULT_MAX_BODY_BYTES: u64 = 256 * 1024;
+
+/// The largest request body accepted, from `MAX_BODY_BYTES` (default 256 KiB).
+fn max_body_bytes() -> u64 {
+    static MAX: OnceLock<u64> = OnceLock::new();
+    *MAX.get_or_init(|| match env::var("MAX_BODY_BYTES") {
+        Ok(value) => match value.parse::<u64>() {
+            Ok(max) if max > 0 => max,
+            _ => {
+                log::warn!("MAX_BODY_BYTES must be a positive number of bytes, using default of {}", DEFAULT_MAX_BODY_BYTES);
+                DEFAULT_MAX_BODY_BYTES
+            },
+        },
+        Err(_) => DEFAULT_MAX_BODY_BYTES,
+    })
+}
+
+/// Deserializes a JSON body of at most `MAX_BODY_BYTES`. Larger bodies are refused with a 413
+/// before any of it is read, and bodies without a `Content-Length` with a 411.
 pub fn json_body<T>() -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone
 where
     T: Send + serde::de::DeserializeOwned + 'static,
 {
-    warp::body::json()
+    warp::body::content_length_limit(max_body_bytes()).and(warp::body::json())
+}
+
+/// Starts a transaction for handlers that check access and then write, so both steps see the
+/// same state. Dropping it without committing rolls back.
+pub async fn begin(pool: &sqlx::PgPool) -> Result<sqlx::Transaction<'static, sqlx::Postgres>, warp::Rejection> {
+    pool.begin()
+        .await
+        .map_err(db_rejection)
 }
 
-pub async fn user_owns_budget<E>(
+/// The user's role on a budget (`"owner"` or `"member"`), or `None` if they aren't associated.
+pub async fn budget_role(
     user_id: i32,
     budget_id: i32,
     pool: &sqlx::PgPool,
+) -> Result<Option<String>, warp::Rejection> {
+    let result = sqlx::query!(
+        "SELECT role FROM user_budgets WHERE userid = $1 AND budgetid = $2",
+        user_id,
+        budget_id
+    )
+        .fetch_optional(pool)
+        .await
+        .map_err(db_rejection)?;
+
+    Ok(result.map(|record| record.role))
+}
+
+/// Checks membership, locking the `user_budgets` row so that, inside a transaction, the
+/// association can't be removed before the transaction ends.
+pub async fn user_owns_budget<'c, X, E>(
+    user_id: i32,
+    budget_id: i32,
+    executor: X,
     error: E
 ) -> Result<bool, warp::Rejection>
 where
+    X: sqlx::PgExecutor<'c>,
     E: warp::reject::Reject + Send + Sync + 'static,
 {
     let result = sqlx::query!(
-        "SELECT 1 as exists FROM user_budgets WHERE userid = $1 AND budgetid = $2",
+        "SELECT 1 as exists FROM user_budgets WHERE userid = $1 AND budgetid = $2 FOR SHARE",
         user_id,
         budget_id
     )
-        .fetch_optional(pool)
+        .fetch_optional(executor)
+        .await
+        .map_err(|_| warp::reject::custom(error))?;
+
+    Ok(result.is_some())
+}
+
+/// Like `user_owns_budget`, but only true when the user holds the `owner` role rather than
+/// being a member. The row stays locked for the rest of a surrounding transaction.
+pub async fn user_is_budget_owner<'c, X, E>(
+    user_id: i32,
+    budget_id: i32,
+    executor: X,
+    error: E
+) -> Result<bool, warp::Rejection>
+where
+    X: sqlx::PgExecutor<'c>,
+    E: warp::reject::Reject + Send + Sync + 'static,
+{
+    let result = sqlx::query!(
+        "SELECT 1 as exists FROM user_budgets WHERE userid = $1 AND budgetid = $2 AND role = 'owner' FOR SHARE",
+        user_id,
+        budget_id
+    )
+        .fetch_optional(executor)
         .await
         .map_err(|_| warp::reject::custom(error))?;
 
     Ok(result.is_some())
 }
+
+/// Returns the subset of `budget_ids` the user is associated with, in a single query.
+pub async fn user_owns_budgets(
+    user_id: i32,
+    budget_ids: &[i32],
+    pool: &sqlx::PgPool,
+) -> Result<HashSet<i32>, warp::Rejection> {
+    let owned = sqlx::query!(
+        "SELECT budgetid FROM user_budgets WHERE userid = $1 AND budgetid = ANY($2)",
+        user_id,
+        budget_ids
+    )
+        .fetch_all(pool)
+        .await
+        .map_err(db_rejection)?
+        .into_iter()
+        .map(|record| record.budgetid)
+        .collect();
+
+    Ok(owned)
+}
+
+/// Looks up membership and lock state together: `None` if the user isn't associated with the
+/// budget, otherwise whether the budget is locked against edits. Both rows stay locked for
+/// the rest of a surrounding transaction.
+pub async fn budget_lock_state<'c, X>(
+    user_id: i32,
+    budget_id: i32,
+    executor: X,
+) -> Result<Option<bool>, warp::Rejection>
+where
+    X: sqlx::PgExecutor<'c>,
+{
+    let result = sqlx::query!(
+        "SELECT b.locked FROM budgets b JOIN user_budgets ub ON ub.budgetid = b.id WHERE ub.userid = $1 AND b.id = $2 FOR SHARE",
+        user_id,
+        budget_id
+    )
+        .fetch_optional(executor)
+        .await
+        .map_err(db_rejection)?;
+
+    Ok(result.map(|record| record.locked))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    #[test]
+    fn pool_options_apply_configured_timeouts() {
+        let options = pool_options_from(None, None, None);
+        assert_eq!(options.get_max_connections(), 10);
+        assert_eq!(options.get_idle_timeout(), Some(Duration::from_secs(600)));
+        assert_eq!(options.get_max_lifetime(), Some(Duration::from_secs(1800)));
+
+        let options = pool_options_from(Some("3"), Some("30"), Some("0"));
+        assert_eq!(options.get_max_connections(), 3);
+        assert_eq!(options.get_idle_timeout(), Some(Duration::from_secs(30)));
+        assert_eq!(options.get_max_lifetime(), None);
+
+        let options = pool_options_from(Some("many"), Some("soon"), Some("-1"));
+        assert_eq!(options.get_max_connections(), 10);
+        assert_eq!(options.get_idle_timeout(), Some(Duration::from_secs(600)));
+        assert_eq!(options.get_max_lifetime(), Some(Duration::from_secs(1800)));
+    }
+
+    #[test]
+    fn parse_id_list_reads_comma_separated_ids() {
+        assert_eq!(parse_id_list("1,2, 3").unwrap(), vec![1, 2, 3]);
+        assert!(parse_id_list("1,two").is_err());
+        assert!(parse_id_list("").is_err());
+    }
+
+    #[sqlx::test]
+    async fn user_owns_budgets_returns_only_owned_ids(pool: sqlx::PgPool) {
+        let user = test_support::user(&pool, "owner").await;
+        let other = test_support::user(&pool, "other").await;
+        let owned = test_support::budget(&pool, user).await;
+        let foreign = test_support::budget(&pool, other).await;
+
+        let result = user_owns_budgets(user, &[owned, foreign, foreign + 1000], &pool).await.unwrap();
+
+        assert_eq!(result, HashSet::from([owned]));
+    }
+
+    #[test]
+    fn round_amount_rounds_half_up_to_the_currency_scale() {
+        let amount = |value: &str| value.parse::<BigDecimal>().unwrap();
+
+        assert_eq!(round_amount(&amount("10.005"), "USD").to_string(), "10.01");
+        assert_eq!(round_amount(&amount("10.004"), "EUR").to_string(), "10.00");
+        assert_eq!(round_amount(&amount("-10.005"), "USD").to_string(), "-10.01");
+        assert_eq!(round_amount(&amount("1234.5"), "JPY").to_string(), "1235");
+        assert_eq!(round_amount(&amount("7"), "GBP").to_string(), "7.00");
+    }
+
+    /// Runs `statement` on its own connection while `tx` holds its locks, checks it is still
+    /// waiting after a moment, then commits `tx` and returns once the statement has finished.
+    async fn blocks_until_commit(pool: &sqlx::PgPool, tx: sqlx::Transaction<'static, sqlx::Postgres>, statement: &'static str, budget: i32) {
+        let other = pool.clone();
+        let mut concurrent = tokio::spawn(async move {
+            sqlx::query(statement).bind(budget).execute(&other).await.unwrap();
+        });
+
+        assert!(tokio::time::timeout(Duration::from_millis(300), &mut concurrent).await.is_err(), "{}", statement);
+        tx.commit().await.unwrap();
+        concurrent.await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn lock_checks_hold_the_budget_until_the_write_commits(pool: sqlx::PgPool) {
+        let user = test_support::user(&pool, "owner").await;
+        let budget = test_support::budget(&pool, user).await;
+
+        let mut tx = begin(&pool).await.unwrap();
+        assert_eq!(budget_lock_state(user, budget, &mut *tx).await.unwrap(), Some(false));
+        sqlx::query("INSERT INTO expenses (budgetid, date, description, amount) VALUES ($1, '2024-03-01', 'Coffee', 3)")
+            .bind(budget)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        blocks_until_commit(&pool, tx, "UPDATE budgets SET locked = true WHERE id = $1", budget).await;
+
+        // The expense went in while the budget was unlocked, and the lock followed it.
+        let (count, locked): (i64, bool) = sqlx::query_as(
+            "SELECT (SELECT COUNT(*) FROM expenses WHERE budgetid = $1), locked FROM budgets WHERE id = $1",
+        )
+            .bind(budget)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!((count, locked), (1, true));
+    }
+
+    #[sqlx::test]
+    async fn owner_checks_hold_the_association_until_the_write_commits(pool: sqlx::PgPool) {
+        let owner = test_support::user(&pool, "owner").await;
+        let member = test_support::user(&pool, "member").await;
+        let budget = test_support::budget(&pool, owner).await;
+
+        let mut tx = begin(&pool).await.unwrap();
+        assert!(user_is_budget_owner(owner, budget, &mut *tx, ServiceError::Unauthorized).await.unwrap());
+        sqlx::query("INSERT INTO user_budgets (userid, budgetid, role) VALUES ($1, $2, 'member')")
+            .bind(member)
+            .bind(budget)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        blocks_until_commit(&pool, tx, "DELETE FROM user_budgets WHERE budgetid = $1 AND role = 'owner'", budget).await;
+
+        let roles: Vec<String> = sqlx::query_scalar("SELECT role FROM user_budgets WHERE budgetid = $1")
+            .bind(budget)
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(roles, vec!["member"]);
+    }
+
+    #[test]
+    fn parse_date_reads_calendar_dates_only() {
+        assert_eq!(parse_date("2024-02-29"), Some(Date::from_calendar_date(2024, Month::February, 29).unwrap()));
+        assert_eq!(parse_date("2023-02-29"), None);
+        assert_eq!(parse_date("2024-2-01"), None);
+        assert_eq!(parse_date("2024-02"), None);
+        assert_eq!(parse_date("yesterday"), None);
+    }
+
+    #[test]
+    fn validate_date_range_rejects_reversed_dates() {
+        let start = Date::from_calendar_date(2024, Month::March, 1).unwrap();
+        let end = Date::from_calendar_date(2024, Month::January, 1).unwrap();
+
+        assert!(validate_date_range(start, Some(end)).is_err());
+        assert!(validate_date_range(end, Some(start)).is_ok());
+        assert!(validate_date_range(start, Some(start)).is_ok());
+        assert!(validate_date_range(start, None).is_ok());
+    }
+
+    #[tokio::test]
+    async fn path_prefix_mounts_routes_under_the_base_path() {
+        let routes = path_prefix("/api/v1/").and(warp::path!("health")).map(|| "ok");
+
+        assert_eq!(warp::test::request().path("/api/v1/health").reply(&routes).await.status(), StatusCode::OK);
+        assert_eq!(warp::test::request().path("/health").reply(&routes).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(warp::test::request().path("/api/health").reply(&routes).await.status(), StatusCode::NOT_FOUND);
+
+        let unprefixed = path_prefix("").and(warp::path!("health")).map(|| "ok");
+        assert_eq!(warp::test::request().path("/health").reply(&unprefixed).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn deprecated_routes_carry_their_own_sunset() {
+        let flagged = deprecated(warp::path!("old").map(|| "old"), Some("Wed, 01 Jan 2025 00:00:00 GMT".into()));
+        let aliases = deprecated(flagged.or(warp::path!("alias").map(|| "alias")), Some("Thu, 01 Jan 2026 00:00:00 GMT".into()));
+        let routes = aliases.or(warp::path!("current").map(|| "current"));
+
+        let response = warp::test::request().path("/old").reply(&routes).await;
+        assert_eq!(response.headers()["Deprecation"], "true");
+        assert_eq!(response.headers()["Sunset"], "Wed, 01 Jan 2025 00:00:00 GMT");
+
+        let response = warp::test::request().path("/alias").reply(&routes).await;
+        assert_eq!(response.headers()["Deprecation"], "true");
+        assert_eq!(response.headers()["Sunset"], "Thu, 01 Jan 2026 00:00:00 GMT");
+
+        let response = warp::test::request().path("/current").reply(&routes).await;
+        assert!(!response.headers().contains_key("Deprecation"));
+        assert!(!response.headers().contains_key("Sunset"));
+    }
+
+    #[tokio::test]
+    async fn compressed_honours_accept_encoding() {
+        let routes = compressed(warp::any().map(|| warp::reply::json(&vec!["expense"; 200])));
+
+        let response = warp::test::request().header("accept-encoding", "gzip, deflate").reply(&routes).await;
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        assert_eq!(response.headers()["vary"], "Accept-Encoding");
+
+        let response = warp::test::request().header("accept-encoding", "gzip;q=0, deflate").reply(&routes).await;
+        assert_eq!(response.headers()["content-encoding"], "deflate");
+
+        let response = warp::test::request().reply(&routes).await;
+        assert!(!response.headers().contains_key("content-encoding"));
+        assert!(response.body().starts_with(b"[\"expense\""));
+    }
+}
@@ -0,0 +1,176 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use serde::Serialize;
+
+/// How long one delivery may take, connection included.
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Deliveries made before giving up, one second apart.
+const ATTEMPTS: u32 = 2;
+
+/// Whether `ip` is reachable on the public internet, as opposed to this host, its network or the
+/// cloud metadata service at 169.254.169.254. IPv4-mapped IPv6 addresses are judged as IPv4.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        || a == 0
+        // Carrier-grade NAT, 100.64.0.0/10.
+        || (a == 100 && (64..128).contains(&b))
+        // Reserved, 240.0.0.0/4.
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local, fc00::/7.
+        || (first & 0xfe00) == 0xfc00
+        // Link-local, fe80::/10.
+        || (first & 0xffc0) == 0xfe80
+        // Documentation, 2001:db8::/32.
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+/// Resolves `url`'s host and checks that every address it names is public, so a budget can't
+/// aim its webhook at internal services. Errors are phrased for the `webhook_url` setting.
+pub async fn check_destination(url: &str) -> Result<(), String> {
+    let invalid = || "webhook_url must be an http or https URL".to_string();
+    let url = reqwest::Url::parse(url).map_err(|_| invalid())?;
+    let host = url.host_str().ok_or_else(invalid)?;
+
+    let addresses: Vec<IpAddr> = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => tokio::net::lookup_host((host, 0))
+            .await
+            .map_err(|_| "webhook_url host could not be resolved".to_string())?
+            .map(|address| address.ip())
+            .collect(),
+    };
+
+    if addresses.is_empty() || !addresses.into_iter().all(is_public) {
+        return Err("webhook_url must point to a public address".into());
+    }
+    Ok(())
+}
+
+/// Resolves names for the webhook client, dropping any address that isn't public. This holds
+/// even if a name that passed `check_destination` later resolves somewhere else.
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addresses: Vec<_> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|address| is_public(address.ip()))
+                .collect();
+            if addresses.is_empty() {
+                return Err("no public address".into());
+            }
+            Ok(Box::new(addresses.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+fn client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none())
+            .dns_resolver(Arc::new(PublicResolver))
+            .build()
+            .expect("failed to build webhook client")
+    })
+}
+
+/// POSTs `payload` as JSON to `url` in the background. A non-2xx response or a failed request
+/// is retried once and then logged; the caller never waits on or hears about the delivery.
+/// A URL that no longer points to a public address is skipped. The URL is left out of the log
+/// since it may carry a secret.
+pub fn notify<T: Serialize>(url: String, budget_id: i32, payload: &T) {
+    let body = match serde_json::to_vec(payload) {
+        Ok(body) => body,
+        Err(e) => {
+            log::error!("Failed to serialize webhook payload for budget {}: {}", crate::logging::id(budget_id), e);
+            return;
+        },
+    };
+
+    tokio::spawn(async move {
+        if let Err(e) = check_destination(&url).await {
+            log::warn!("Webhook for budget {} not sent: {}", crate::logging::id(budget_id), e);
+            return;
+        }
+
+        for attempt in 1..=ATTEMPTS {
+            let result = client()
+                .post(&url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone())
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+
+            match result {
+                Ok(_) => return,
+                Err(e) if attempt < ATTEMPTS => {
+                    log::debug!("Webhook for budget {} failed, retrying: {}", crate::logging::id(budget_id), e.without_url());
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                },
+                Err(e) => log::warn!("Webhook for budget {} failed: {}", crate::logging::id(budget_id), e.without_url()),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::dns::Resolve;
+
+    #[test]
+    fn is_public_rejects_internal_addresses() {
+        for ip in [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1",
+            "0.0.0.0", "255.255.255.255", "::1", "::", "fd00::1", "fe80::1", "::ffff:127.0.0.1", "::ffff:169.254.169.254",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["93.184.216.34", "100.128.0.1", "2606:2800:220:1::1", "::ffff:93.184.216.34"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn check_destination_rejects_internal_hosts() {
+        for url in ["http://169.254.169.254/latest/meta-data", "http://127.0.0.1:8080/", "https://[::1]/", "http://localhost/hook"] {
+            assert_eq!(check_destination(url).await, Err("webhook_url must point to a public address".into()), "{}", url);
+        }
+        assert!(check_destination("http://93.184.216.34/hook").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn resolver_drops_internal_addresses() {
+        assert!(PublicResolver.resolve("localhost".parse().unwrap()).await.is_err());
+    }
+}